@@ -0,0 +1,909 @@
+use colored::*;
+use glob::glob;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions, create_dir_all, read, read_to_string, write};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::patch::PatchEntry;
+use crate::pkgbuild::read_package_info;
+
+// parts of environment variable names whose values are not written to the build logs
+const HIDDEN_VARIABLES: [&str; 5] = ["TOKEN", "SECRET", "PASSWORD", "CREDENTIAL", "KEY"];
+
+// result of the build of one package
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildResult {
+    Built,
+    UpToDate,
+    // reason why the package wasn't built
+    Skipped(String),
+    // path to the build log
+    Failed(String),
+}
+
+// struct to contain the result of a package for the summary of all builds
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    pub package: String,
+    pub version: String,
+    pub result: BuildResult,
+}
+
+impl BuildReport {
+    pub fn new(package: &str, version: &str, result: BuildResult) -> Self {
+        BuildReport {
+            package: package.to_string(),
+            version: version.to_string(),
+            result,
+        }
+    }
+}
+
+// package that is prepared in the build directory and waits for its build
+#[derive(Debug, Clone)]
+pub struct BuildJob {
+    pub package: String,
+    pub version: String,
+    pub pkg_build_dir: String,
+    pub patches: Vec<PatchEntry>,
+    // name of the local repository the built packages are added to
+    pub repository: String,
+    // pkgbase, pkgnames and provides of the PKGBUILD
+    pub names: Vec<String>,
+    // depends and makedepends without version constraints
+    pub depends: Vec<String>,
+}
+
+impl BuildJob {
+    pub fn new(
+        package: &str,
+        version: &str,
+        pkg_build_dir: &str,
+        patches: Vec<PatchEntry>,
+        repository: &str,
+    ) -> Self {
+        //! takes the prepared package and reads the names and dependencies from its PKGBUILD
+        //! if the PKGBUILD can't be parsed, the package is built without ordering
+        let mut job = BuildJob {
+            package: package.to_string(),
+            version: version.to_string(),
+            pkg_build_dir: pkg_build_dir.to_string(),
+            patches,
+            repository: repository.to_string(),
+            names: vec![package.to_string()],
+            depends: Vec::new(),
+        };
+
+        match read_package_info(pkg_build_dir) {
+            Ok(info) => {
+                job.names.push(info.pkgbase);
+                job.names.extend(info.pkgnames);
+                job.names.extend(
+                    info.provides
+                        .iter()
+                        .map(|provided| dependency_name(provided).to_string()),
+                );
+                job.depends = info
+                    .depends
+                    .iter()
+                    .chain(info.makedepends.iter())
+                    .map(|dependency| dependency_name(dependency).to_string())
+                    .collect();
+            }
+            Err(e) => println!(
+                "{}",
+                format!(
+                    "Dependencies of {} couldn't be read: {} --> building without ordering",
+                    package, e
+                )
+                .yellow()
+            ),
+        }
+
+        job
+    }
+}
+
+fn dependency_name(dependency: &str) -> &str {
+    //! takes a dependency (e.g. wlroots>=0.18) and returns the package name
+    dependency
+        .split(['<', '>', '='])
+        .next()
+        .unwrap_or(dependency)
+        .trim()
+}
+
+pub fn dependency_graph(jobs: &[BuildJob]) -> Vec<Vec<usize>> {
+    //! returns the indices of the jobs every job depends on
+    //! dependencies that aren't built in this run are resolved by pacman and ignored
+    jobs.iter()
+        .enumerate()
+        .map(|(index, job)| {
+            jobs.iter()
+                .enumerate()
+                .filter(|(other_index, other)| {
+                    *other_index != index
+                        && job
+                            .depends
+                            .iter()
+                            .any(|dependency| other.names.contains(dependency))
+                })
+                .map(|(other_index, _)| other_index)
+                .collect()
+        })
+        .collect()
+}
+
+pub fn topological_order(graph: &[Vec<usize>]) -> (Vec<usize>, Vec<Vec<usize>>) {
+    //! takes the dependencies of every job and returns the order in which the jobs can be built
+    //! and all dependency cycles
+    //! jobs that are part of a cycle or depend on a cycle are not part of the order
+    //! if several jobs can be built, the one defined first in the config is used
+    let mut missing: Vec<usize> = graph
+        .iter()
+        .map(|dependencies| dependencies.len())
+        .collect();
+    let mut order: Vec<usize> = Vec::new();
+    let mut ready: Vec<usize> = (0..graph.len()).filter(|&job| missing[job] == 0).collect();
+
+    while let Some(position) = ready
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, job)| **job)
+        .map(|(position, _)| position)
+    {
+        let job = ready.remove(position);
+        order.push(job);
+        for (dependent, dependencies) in graph.iter().enumerate() {
+            if dependencies.contains(&job) {
+                missing[dependent] -= 1;
+                if missing[dependent] == 0 {
+                    ready.push(dependent);
+                }
+            }
+        }
+    }
+
+    // every remaining job has a remaining dependency --> following them always ends in a cycle
+    let remaining: Vec<usize> = (0..graph.len())
+        .filter(|job| !order.contains(job))
+        .collect();
+    let mut cycles: Vec<Vec<usize>> = Vec::new();
+
+    for &start in &remaining {
+        let mut path: Vec<usize> = vec![start];
+        let mut job = start;
+        while let Some(&next) = graph[job]
+            .iter()
+            .find(|dependency| remaining.contains(dependency))
+        {
+            if let Some(position) = path.iter().position(|&visited| visited == next) {
+                let cycle = path[position..].to_vec();
+                // the same cycle is found from every job that leads to it
+                if !cycles
+                    .iter()
+                    .any(|known| known.contains(&cycle[0]) && known.len() == cycle.len())
+                {
+                    cycles.push(cycle);
+                }
+                break;
+            }
+            path.push(next);
+            job = next;
+        }
+    }
+
+    (order, cycles)
+}
+
+// next step of the builds
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    // the job can be built
+    Build(usize),
+    // the job is skipped since the dependency wasn't built
+    Skip(usize, usize),
+}
+
+// struct to contain the state of the jobs during the builds
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    graph: Vec<Vec<usize>>,
+    // jobs in the order in which they are started
+    pub pending: Vec<usize>,
+    finished: Vec<usize>,
+    // jobs that weren't built successfully (including the jobs that aren't part of the order)
+    failed: Vec<usize>,
+}
+
+impl Schedule {
+    pub fn new(graph: &[Vec<usize>], order: &[usize]) -> Self {
+        Schedule {
+            graph: graph.to_vec(),
+            pending: order.to_vec(),
+            finished: Vec::new(),
+            failed: (0..graph.len())
+                .filter(|job| !order.contains(job))
+                .collect(),
+        }
+    }
+
+    pub fn next(&mut self) -> Option<Step> {
+        //! returns the next job whose dependencies are done, a job with a failed dependency is
+        //! skipped and counts as failed, so that its dependents are skipped as well
+        //! returns None if all pending jobs wait for running builds
+        let position = self.pending.iter().position(|job| {
+            self.graph[*job].iter().all(|dependency| {
+                self.finished.contains(dependency) || self.failed.contains(dependency)
+            })
+        })?;
+        let job = self.pending.remove(position);
+
+        match self.graph[job]
+            .iter()
+            .find(|dependency| self.failed.contains(dependency))
+        {
+            Some(&dependency) => {
+                self.failed.push(job);
+                Some(Step::Skip(job, dependency))
+            }
+            None => Some(Step::Build(job)),
+        }
+    }
+
+    pub fn finish(&mut self, job: usize) {
+        self.finished.push(job);
+    }
+
+    pub fn fail(&mut self, job: usize) {
+        self.failed.push(job);
+    }
+
+    pub fn has_pending_dependents(&self, job: usize) -> bool {
+        //! returns true if a job that isn't started yet depends on the job
+        self.pending
+            .iter()
+            .any(|dependent| self.graph[*dependent].contains(&job))
+    }
+}
+
+fn timestamp() -> String {
+    //! returns the current local time in a format that can be sorted and used in file names
+    chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string()
+}
+
+fn hash_file(file_path: &str) -> String {
+    //! returns the sha256 of the file or "none" if the file couldn't be read
+    match read(file_path) {
+        Ok(content) => format!("{:x}", Sha256::digest(&content)),
+        Err(_) => "none".to_string(),
+    }
+}
+
+pub fn log_file_path(build_dir: &str, package: &str) -> String {
+    //! returns the path of the log file for a new build of the package
+    //! build_dir/logs/<package>/<timestamp>.log
+    format!("{}/logs/{}/{}.log", build_dir, package, timestamp())
+}
+
+pub fn write_log_header(
+    log_file: &str,
+    package: &str,
+    version: &str,
+    pkg_build_dir: &str,
+    patches: &[PatchEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    //! creates the log file and writes the package, the hash of the PKGBUILD, the applied patches
+    //! and the environment of the build
+    if let Some((log_dir, _)) = log_file.rsplit_once("/") {
+        create_dir_all(log_dir)?;
+    }
+    let mut log = File::create(log_file)?;
+
+    writeln!(log, "package: {}", package)?;
+    writeln!(log, "version: {}", version)?;
+    writeln!(log, "date: {}", timestamp())?;
+    writeln!(
+        log,
+        "PKGBUILD sha256: {}",
+        hash_file(&format!("{}/PKGBUILD", pkg_build_dir))
+    )?;
+
+    writeln!(log, "patches:")?;
+    for patch in patches {
+        writeln!(
+            log,
+            "  {} (strip {}, directory {}) sha256: {}",
+            patch.file,
+            patch.strip,
+            if patch.directory.is_empty() {
+                "prepare()"
+            } else {
+                &patch.directory
+            },
+            hash_file(&format!("{}/{}", pkg_build_dir, patch.file))
+        )?;
+    }
+
+    writeln!(log, "environment:")?;
+    let mut variables: Vec<(String, String)> = std::env::vars().collect();
+    variables.sort();
+    for (name, value) in variables {
+        if HIDDEN_VARIABLES
+            .iter()
+            .any(|hidden| name.to_uppercase().contains(hidden))
+        {
+            writeln!(log, "  {}=<hidden>", name)?;
+        } else {
+            writeln!(log, "  {}={}", name, value)?;
+        }
+    }
+
+    writeln!(log, "output:")?;
+
+    Ok(())
+}
+
+pub fn run_logged_command(
+    commands: Vec<String>,
+    log_file: &str,
+    output_prefix: &str,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    //! takes a vector of bash commands, the path to the log file and a prefix for the printed
+    //! lines, executes the commands and prints the output while appending it to the log file
+    //! the commands stop at the first failing command
+    //! returns the exit code of the commands
+    if let Some((log_dir, _)) = log_file.rsplit_once("/") {
+        create_dir_all(log_dir)?;
+    }
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+
+    // stderr is redirected to stdout to keep the order of the output in the log
+    let joined_command = format!("exec 2>&1; {}", commands.join(" && "));
+    let mut cmd = Command::new("bash")
+        .arg("-c")
+        .arg(joined_command)
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    if let Some(stdout) = cmd.stdout.take() {
+        let reader = BufReader::new(stdout);
+        for line in reader.lines() {
+            let line = line?;
+            println!("{}{}", output_prefix, line);
+            writeln!(log, "{}", line)?;
+        }
+    }
+
+    let status = cmd.wait()?;
+    // a process that was killed by a signal has no exit code
+    let exit_code = status.code().unwrap_or(-1);
+    writeln!(log, "exit code: {}", exit_code)?;
+
+    Ok(exit_code)
+}
+
+// struct to contain the settings of the clean chroot that is used for builds
+#[derive(Debug, Clone)]
+pub struct Chroot {
+    // directory containing the root of the chroot and the working copies of the builds
+    pub dir: String,
+    // directories of the local repositories that are mounted into the chroot
+    pub local_repo_dirs: Vec<String>,
+}
+
+impl Chroot {
+    fn root(&self) -> String {
+        format!("{}/root", self.dir)
+    }
+
+    fn pacman_conf(&self) -> String {
+        format!("{}/pacman.conf", self.dir)
+    }
+
+    pub fn nspawn(&self, command: &str) -> String {
+        //! returns the command that runs a command in the root of the chroot with the local
+        //! repositories mounted
+        format!(
+            "sudo arch-nspawn -C {} {} {} {}",
+            self.pacman_conf(),
+            self.root(),
+            self.bind_arguments("--bind-ro="),
+            command
+        )
+    }
+
+    pub fn bind_arguments(&self, option: &str) -> String {
+        //! returns the option for every directory of the local repositories
+        self.local_repo_dirs
+            .iter()
+            .map(|dir| format!("{}{}", option, dir))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+pub fn chroot_pacman_conf(
+    local_repos: &[(String, String)],
+    siglevel: &str,
+    servers: &[String],
+) -> String {
+    //! takes the local repositories (name and directory), their SigLevel and the servers of the
+    //! official repositories and returns the pacman.conf for the chroot
+    //! the local repositories are defined first, so that patched packages replace the official ones
+    let mut content = String::from(
+        "[options]\nArchitecture = auto\nSigLevel = Required DatabaseOptional\nLocalFileSigLevel = Optional\nParallelDownloads = 5\n",
+    );
+
+    for (name, directory) in local_repos {
+        content.push_str(&format!(
+            "\n[{}]\nSigLevel = {}\nServer = file://{}\n",
+            name, siglevel, directory
+        ));
+    }
+
+    for repository in ["core", "extra", "multilib"] {
+        content.push_str(&format!("\n[{}]\n", repository));
+        for server in servers {
+            content.push_str(&format!("Server = {}\n", server));
+        }
+    }
+
+    content
+}
+
+pub fn prepare_chroot(
+    chroot: &Chroot,
+    pacman_conf: &str,
+    log_file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    //! writes the pacman.conf of the chroot and creates the root of the chroot with base-devel if
+    //! it doesn't exist
+    //! an existing root is updated (and downgraded if necessary) to the state of the servers, so
+    //! that all builds use the same snapshot
+    create_dir_all(&chroot.dir)?;
+    write(chroot.pacman_conf(), pacman_conf)?;
+
+    let command = if Path::new(&chroot.root()).is_dir() {
+        chroot.nspawn("pacman -Syuu --noconfirm")
+    } else {
+        format!(
+            "sudo mkarchroot -C {} {} base-devel",
+            chroot.pacman_conf(),
+            chroot.root()
+        )
+    };
+
+    match run_logged_command(vec![command], log_file, "")? {
+        0 => Ok(()),
+        exit_code => Err(format!("chroot couldn't be prepared, exit code {}", exit_code).into()),
+    }
+}
+
+pub fn build_package(
+    job: &BuildJob,
+    log_file: &str,
+    chroot: Option<&Chroot>,
+    srcdest: &str,
+    output_prefix: &str,
+) -> Result<(), String> {
+    //! takes the prepared package and the path to the log file and executes a bash process to
+    //! build the package
+    //! with a chroot, the package is built in a clean copy of the chroot, otherwise on the host
+    //! the sources are downloaded to the shared srcdest, so they are only downloaded once
+    //! every line of the output is printed with the prefix to distinguish parallel builds
+    //! returns an error if the build failed
+    let mut commands: Vec<String> = vec![
+        format!("cd {}", job.pkg_build_dir),
+        format!("export SRCDEST={}", srcdest),
+        "updpkgsums".to_string(),
+    ];
+
+    match chroot {
+        // every package uses its own copy of the chroot, so that packages can be built in parallel
+        Some(chroot) => commands.push(format!(
+            "makechrootpkg -c -r {} -l {} {} -- --skippgpcheck",
+            chroot.dir,
+            job.package,
+            chroot.bind_arguments("-D ")
+        )),
+        None => commands.push("makepkg -cCsr --skippgpcheck".to_string()),
+    }
+
+    match run_logged_command(commands, log_file, output_prefix) {
+        Ok(0) => Ok(()),
+        Ok(exit_code) => Err(format!("build failed with exit code {}", exit_code)),
+        Err(e) => Err(format!("build couldn't be started: {}", e)),
+    }
+}
+
+fn build_succeeded(log_file: &str) -> bool {
+    //! returns true if the log file ends with the exit code 0
+    read_to_string(log_file)
+        .map(|content| content.trim_end().ends_with("exit code: 0"))
+        .unwrap_or(false)
+}
+
+pub fn show_logs(
+    build_dir: &str,
+    package: &str,
+    diff: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    //! prints the latest build log of the package
+    //! with diff, the differences between the last successful build and the latest build are
+    //! printed instead
+    let (headline, output) = log_output(build_dir, package, diff)?;
+    println!("{}", headline);
+    print!("{}", output);
+    Ok(())
+}
+
+fn log_output(
+    build_dir: &str,
+    package: &str,
+    diff: bool,
+) -> Result<(ColoredString, String), Box<dyn std::error::Error>> {
+    //! returns the headline and the output of show_logs
+    let mut log_files: Vec<String> = glob(&format!(
+        "{}/logs/{}/*.log",
+        build_dir,
+        glob::Pattern::escape(package)
+    ))?
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.to_string_lossy().to_string())
+    .collect();
+    // the file names are timestamps --> the last file is the latest build
+    log_files.sort();
+
+    let Some(latest) = log_files.last() else {
+        return Err(format!("No build logs found for {}", package).into());
+    };
+
+    if !diff {
+        return Ok((
+            format!("Latest build log: {}", latest).blue(),
+            read_to_string(latest)?,
+        ));
+    }
+
+    if build_succeeded(latest) {
+        return Ok((
+            format!("The latest build of {} succeeded: {}", package, latest).green(),
+            String::new(),
+        ));
+    }
+
+    let Some(last_good) = log_files
+        .iter()
+        .rev()
+        .skip(1)
+        .find(|log| build_succeeded(log))
+    else {
+        return Err(format!("No successful build log found for {}", package).into());
+    };
+
+    let output = Command::new("diff")
+        .args(["-u", last_good, latest])
+        .output()?;
+    Ok((
+        format!("Differences between {} and {}", last_good, latest).blue(),
+        String::from_utf8_lossy(&output.stdout).to_string(),
+    ))
+}
+
+pub fn print_summary(reports: &[BuildReport]) {
+    //! prints a table with the result of every package
+    if reports.is_empty() {
+        return;
+    }
+
+    println!("{}", "\nBuild summary:".blue());
+    for line in summary_lines(reports) {
+        println!("{}", line);
+    }
+}
+
+fn summary_lines(reports: &[BuildReport]) -> Vec<String> {
+    //! returns the header and a line with the result of every package
+    let package_width = reports
+        .iter()
+        .map(|report| report.package.len())
+        .max()
+        .unwrap_or(0)
+        .max("Package".len());
+    let version_width = reports
+        .iter()
+        .map(|report| report.version.len())
+        .max()
+        .unwrap_or(0)
+        .max("Version".len());
+
+    let mut lines: Vec<String> = vec![format!(
+        "{:package_width$}  {:version_width$}  {:10}  Details",
+        "Package", "Version", "Result"
+    )];
+
+    for report in reports {
+        // the padding is added before coloring, otherwise the escape codes count as width
+        let (result, details) = match &report.result {
+            BuildResult::Built => (format!("{:10}", "built").green(), String::new()),
+            BuildResult::UpToDate => (format!("{:10}", "up-to-date").green(), String::new()),
+            BuildResult::Skipped(reason) => (format!("{:10}", "skipped").yellow(), reason.clone()),
+            BuildResult::Failed(log_file) => (
+                format!("{:10}", "failed").red(),
+                format!("log: {}", log_file),
+            ),
+        };
+        let line = format!(
+            "{:package_width$}  {:version_width$}  {}  {}",
+            report.package, report.version, result, details
+        );
+        lines.push(line.trim_end().to_string());
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(package: &str, names: &[&str], depends: &[&str]) -> BuildJob {
+        BuildJob {
+            package: package.to_string(),
+            version: "1.0-1".to_string(),
+            pkg_build_dir: format!("/build/src/{}", package),
+            patches: Vec::new(),
+            repository: "custom".to_string(),
+            names: std::iter::once(package)
+                .chain(names.iter().copied())
+                .map(|name| name.to_string())
+                .collect(),
+            depends: depends.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn dependency_names() {
+        assert_eq!(dependency_name("wlroots>=0.18"), "wlroots");
+        assert_eq!(dependency_name("libfoo.so=1-64"), "libfoo.so");
+        assert_eq!(dependency_name("glibc"), "glibc");
+    }
+
+    #[test]
+    fn dependency_graph_of_split_packages_and_provides() {
+        let jobs = vec![
+            // split package with the pkgbase wlroots-git
+            job(
+                "wlroots-git",
+                &["wlroots-git", "wlroots-git-docs"],
+                &["glibc"],
+            ),
+            job("sway-git", &[], &["wlroots-git-docs", "json-c"]),
+            // depends on a name that is only provided
+            job("foo", &[], &["libbar.so"]),
+            job("bar", &["libbar.so"], &["foo"]),
+            // depends on itself through a split package
+            job("baz", &["baz-libs"], &["baz-libs"]),
+        ];
+
+        assert_eq!(
+            dependency_graph(&jobs),
+            vec![vec![], vec![0], vec![3], vec![2], vec![]]
+        );
+    }
+
+    #[test]
+    fn topological_order_prefers_config_order() {
+        // 0 depends on 2, 1 depends on nothing, 3 depends on 0 and 1
+        let graph = vec![vec![2], vec![], vec![], vec![0, 1]];
+        let (order, cycles) = topological_order(&graph);
+        assert_eq!(order, vec![1, 2, 0, 3]);
+        assert!(cycles.is_empty());
+    }
+
+    #[test]
+    fn topological_order_reports_cycles() {
+        // 0 -> 1 -> 2 -> 0 is a cycle, 3 depends on the cycle, 4 is independent and 5 depends
+        // on itself
+        let graph = vec![vec![1], vec![2], vec![0], vec![1], vec![], vec![5]];
+        let (order, cycles) = topological_order(&graph);
+        assert_eq!(order, vec![4]);
+        assert_eq!(cycles, vec![vec![0, 1, 2], vec![5]]);
+    }
+
+    fn run(schedule: &mut Schedule, failing: &[usize]) -> Vec<Step> {
+        //! builds the jobs one at a time and returns the steps, the failing jobs fail
+        let mut steps: Vec<Step> = Vec::new();
+        while let Some(step) = schedule.next() {
+            if let Step::Build(job) = step {
+                if failing.contains(&job) {
+                    schedule.fail(job);
+                } else {
+                    schedule.finish(job);
+                }
+            }
+            steps.push(step);
+        }
+        steps
+    }
+
+    #[test]
+    fn schedule_skips_dependents_of_failed_builds() {
+        // 1 depends on 0, 2 depends on 1, 3 is independent, 4 depends on 3
+        let graph = vec![vec![], vec![0], vec![1], vec![], vec![3]];
+        let (order, _) = topological_order(&graph);
+        let mut schedule = Schedule::new(&graph, &order);
+
+        assert_eq!(
+            run(&mut schedule, &[0]),
+            vec![
+                Step::Build(0),
+                Step::Skip(1, 0),
+                Step::Skip(2, 1),
+                Step::Build(3),
+                Step::Build(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn schedule_skips_dependents_of_cycles() {
+        // 0 and 1 form a cycle, 2 depends on the cycle, 3 depends on 2
+        let graph = vec![vec![1], vec![0], vec![0], vec![2]];
+        let (order, _) = topological_order(&graph);
+        assert!(order.is_empty());
+
+        // jobs outside of the order are never started and count as failed
+        let mut schedule = Schedule::new(&graph, &[3]);
+        assert_eq!(run(&mut schedule, &[]), vec![Step::Skip(3, 2)]);
+    }
+
+    #[test]
+    fn schedule_waits_for_running_builds() {
+        let graph = vec![vec![], vec![0], vec![]];
+        let mut schedule = Schedule::new(&graph, &[0, 1, 2]);
+
+        // 1 has to wait until 0 is built, 2 can be built in parallel
+        assert_eq!(schedule.next(), Some(Step::Build(0)));
+        assert_eq!(schedule.next(), Some(Step::Build(2)));
+        assert_eq!(schedule.next(), None);
+        assert!(schedule.has_pending_dependents(0));
+
+        schedule.finish(0);
+        assert_eq!(schedule.next(), Some(Step::Build(1)));
+        assert!(!schedule.has_pending_dependents(0));
+        assert!(schedule.pending.is_empty());
+    }
+
+    #[test]
+    fn summary_of_all_results() {
+        colored::control::set_override(false);
+        let reports = vec![
+            BuildReport::new("wlroots-git", "0.19.0-1", BuildResult::Built),
+            BuildReport::new("nano", "", BuildResult::UpToDate),
+            BuildReport::new(
+                "sway-git",
+                "1:1.10-1",
+                BuildResult::Skipped("dependency wlroots-git wasn't built".to_string()),
+            ),
+            BuildReport::new(
+                "foo",
+                "2.0-1",
+                BuildResult::Failed("/build/logs/foo/2024-11-01_10-00-00.log".to_string()),
+            ),
+        ];
+
+        assert_eq!(
+            summary_lines(&reports),
+            vec![
+                "Package      Version   Result      Details",
+                "wlroots-git  0.19.0-1  built",
+                "nano                   up-to-date",
+                "sway-git     1:1.10-1  skipped     dependency wlroots-git wasn't built",
+                "foo          2.0-1     failed      log: /build/logs/foo/2024-11-01_10-00-00.log",
+            ]
+        );
+    }
+
+    fn temporary_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("nompac-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn log_header_hides_secrets() {
+        let dir = temporary_dir("log-header");
+        write(format!("{}/PKGBUILD", dir), "pkgname=foo\n").unwrap();
+        write(format!("{}/fix.patch", dir), "--- a\n+++ b\n").unwrap();
+        std::env::set_var("NOMPAC_TEST_TOKEN", "secret-value");
+
+        let log_file = format!("{}/logs/foo/2024-11-01_10-00-00.log", dir);
+        let patches = vec![PatchEntry::new("fix.patch")];
+        write_log_header(&log_file, "foo", "1.0-1", &dir, &patches).unwrap();
+        let log = read_to_string(&log_file).unwrap();
+
+        assert!(log.starts_with("package: foo\nversion: 1.0-1\ndate: "));
+        assert!(log.contains(&format!(
+            "PKGBUILD sha256: {:x}\n",
+            Sha256::digest(b"pkgname=foo\n")
+        )));
+        assert!(log.contains(&format!(
+            "patches:\n  fix.patch (strip 1, directory prepare()) sha256: {:x}\n",
+            Sha256::digest(b"--- a\n+++ b\n")
+        )));
+        assert!(log.contains("  NOMPAC_TEST_TOKEN=<hidden>\n"));
+        assert!(!log.contains("secret-value"));
+        assert!(log.ends_with("output:\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logs_and_differences_to_the_last_successful_build() {
+        colored::control::set_override(false);
+        let dir = temporary_dir("logs");
+        let log_dir = format!("{}/logs/foo", dir);
+        create_dir_all(&log_dir).unwrap();
+        let good = format!("{}/2024-11-01_10-00-00.log", log_dir);
+        let failed = format!("{}/2024-11-02_10-00-00.log", log_dir);
+        let latest = format!("{}/2024-11-03_10-00-00.log", log_dir);
+
+        assert!(log_output(&dir, "foo", false).is_err());
+
+        write(
+            &good,
+            "package: foo\nversion: 1.0-1\noutput:\nexit code: 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            log_output(&dir, "foo", true).unwrap().0.to_string(),
+            format!("The latest build of foo succeeded: {}", good)
+        );
+
+        write(
+            &failed,
+            "package: foo\nversion: 1.1-1\noutput:\nexit code: 1\n",
+        )
+        .unwrap();
+        write(
+            &latest,
+            "package: foo\nversion: 1.1-2\noutput:\nexit code: 2\n",
+        )
+        .unwrap();
+        assert!(!build_succeeded(&latest));
+
+        let (headline, output) = log_output(&dir, "foo", false).unwrap();
+        assert_eq!(
+            headline.to_string(),
+            format!("Latest build log: {}", latest)
+        );
+        assert_eq!(output, read_to_string(&latest).unwrap());
+
+        // the failed build in between is skipped
+        let (headline, output) = log_output(&dir, "foo", true).unwrap();
+        assert_eq!(
+            headline.to_string(),
+            format!("Differences between {} and {}", good, latest)
+        );
+        assert!(output.contains("-version: 1.0-1\n+version: 1.1-2\n"));
+        assert!(output.contains("-exit code: 0\n+exit code: 2\n"));
+
+        // without a successful build there is nothing to compare with
+        write(
+            &good,
+            "package: foo\nversion: 1.0-1\noutput:\nexit code: 1\n",
+        )
+        .unwrap();
+        assert!(log_output(&dir, "foo", true).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use colored::*;
+use reqwest::StatusCode;
+use reqwest::header::RANGE;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{
+    File, OpenOptions, copy, create_dir_all, metadata, read_dir, read_to_string, remove_file,
+    rename, write,
+};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// struct to contain the download cache in build_dir/cache
+// downloaded files are stored by their sha256 in objects/, index.toml maps the urls to the sha256
+// and makepkg downloads the sources of the PKGBUILDs to sources/
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: String,
+    // maximum size of the cached files in bytes, 0 for no limit
+    max_size: u64,
+}
+
+fn hash_file(file_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    //! returns the sha256 of the file
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(file_path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn touch(file_path: &str) {
+    //! marks the file as recently used for the size limit
+    if let Ok(file) = OpenOptions::new().write(true).open(file_path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+impl Cache {
+    pub fn new(build_dir: &str, max_size_mb: u64) -> Self {
+        Cache {
+            dir: format!("{}/cache", build_dir),
+            max_size: max_size_mb * 1024 * 1024,
+        }
+    }
+
+    fn objects_dir(&self) -> String {
+        format!("{}/objects", self.dir)
+    }
+
+    fn partial_dir(&self) -> String {
+        format!("{}/partial", self.dir)
+    }
+
+    fn index_file(&self) -> String {
+        format!("{}/index.toml", self.dir)
+    }
+
+    pub fn srcdest(&self) -> String {
+        //! returns the SRCDEST directory that is shared by all makepkg runs
+        let srcdest = format!("{}/sources", self.dir);
+        let _ = create_dir_all(&srcdest);
+        srcdest
+    }
+
+    pub fn git_dir(&self, url: &str) -> String {
+        //! returns the directory of the mirror of a git repository
+        format!("{}/git/{:x}", self.dir, Sha256::digest(url.as_bytes()))
+    }
+
+    fn read_index(&self) -> BTreeMap<String, String> {
+        //! returns the url and sha256 of every download, an unreadable index is treated as empty
+        read_to_string(self.index_file())
+            .ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_index(
+        &self,
+        index: &BTreeMap<String, String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write(self.index_file(), toml::to_string(index)?)?;
+        Ok(())
+    }
+
+    pub fn fetch(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        //! takes an url and returns the path of the cached file
+        //! the file is only downloaded if it isn't in the cache or its content doesn't match the
+        //! stored sha256
+        //! a download whose sha256 differs from the stored one is rejected
+        let mut index = self.read_index();
+        let expected = index.get(url).cloned();
+
+        if let Some(hash) = &expected {
+            let object = format!("{}/{}", self.objects_dir(), hash);
+            if Path::new(&object).is_file() {
+                if hash_file(&object)? == *hash {
+                    touch(&object);
+                    println!("Using cached download of {}", url);
+                    return Ok(object);
+                }
+                println!(
+                    "{}",
+                    format!("Cached file of {} is corrupt --> downloading again", url).yellow()
+                );
+                remove_file(&object)?;
+            }
+        }
+
+        create_dir_all(self.objects_dir())?;
+        create_dir_all(self.partial_dir())?;
+        let partial = format!(
+            "{}/{:x}.part",
+            self.partial_dir(),
+            Sha256::digest(url.as_bytes())
+        );
+        self.download(url, &partial)?;
+
+        let hash = hash_file(&partial)?;
+        if let Some(expected) = expected {
+            if expected != hash {
+                remove_file(&partial)?;
+                return Err(format!(
+                    "sha256 of {} doesn't match the cache: expected {}, got {}",
+                    url, expected, hash
+                )
+                .into());
+            }
+        }
+
+        let object = format!("{}/{}", self.objects_dir(), hash);
+        rename(&partial, &object)?;
+        index.insert(url.to_string(), hash);
+        self.write_index(&index)?;
+        println!("Successfully downloaded {}", url);
+
+        self.enforce_limit(&object);
+        Ok(object)
+    }
+
+    fn download(&self, url: &str, partial: &str) -> Result<(), Box<dyn std::error::Error>> {
+        //! downloads the url to the partial file
+        //! an existing partial file of an interrupted download is resumed
+        // local files, e.g. of a mirror on a mounted drive
+        if let Some(path) = url.strip_prefix("file://") {
+            copy(path, partial)?;
+            return Ok(());
+        }
+
+        let offset = metadata(partial)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        let mut request = reqwest::blocking::Client::new().get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = request.send()?;
+
+        let mut file = match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                println!("Resuming download of {} at {} bytes", url, offset);
+                OpenOptions::new().append(true).open(partial)?
+            }
+            // the partial file doesn't fit the file on the server --> start again
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                remove_file(partial)?;
+                return self.download(url, partial);
+            }
+            // the server doesn't support ranges and sends the complete file
+            status if status.is_success() => File::create(partial)?,
+            status => return Err(format!("download of {} failed with {}", url, status).into()),
+        };
+        response.copy_to(&mut file)?;
+
+        Ok(())
+    }
+
+    pub fn enforce_limit(&self, keep: &str) {
+        //! removes the least recently used downloads and sources until the cache is smaller than
+        //! the size limit, the file keep is never removed
+        if self.max_size == 0 {
+            return;
+        }
+
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        for dir in [self.objects_dir(), format!("{}/sources", self.dir)] {
+            let Ok(entries) = read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                // sources of VCS packages are directories and aren't removed
+                if let Ok(metadata) = entry.metadata() {
+                    if metadata.is_file() {
+                        files.push((
+                            metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                            metadata.len(),
+                            entry.path(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut size: u64 = files.iter().map(|file| file.1).sum();
+        files.sort();
+        let mut removed = false;
+        for (_, file_size, path) in files {
+            if size <= self.max_size {
+                break;
+            }
+            if path == Path::new(keep) {
+                continue;
+            }
+            if remove_file(&path).is_ok() {
+                size -= file_size;
+                removed = true;
+            }
+        }
+
+        // the sha256 of a removed download isn't enforced anymore, a server can create the same
+        // archive again with different bytes
+        if removed {
+            let mut index = self.read_index();
+            index
+                .retain(|_, hash| Path::new(&format!("{}/{}", self.objects_dir(), hash)).is_file());
+            if let Err(e) = self.write_index(&index) {
+                eprintln!("Index of the download cache couldn't be written: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    fn temporary_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("nompac-test-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    fn source_file(dir: &str, name: &str, content: &str) -> String {
+        let file_path = format!("{}/{}", dir, name);
+        write(&file_path, content).unwrap();
+        format!("file://{}", file_path)
+    }
+
+    #[test]
+    fn fetch_downloads_and_reuses_files() {
+        let dir = temporary_dir("cache-fetch");
+        let cache = Cache::new(&dir, 0);
+        let url = source_file(&dir, "source.tar.gz", "archive");
+
+        let object = cache.fetch(&url).unwrap();
+        let hash = format!("{:x}", Sha256::digest(b"archive"));
+        assert_eq!(object, format!("{}/cache/objects/{}", dir, hash));
+        assert_eq!(read_to_string(&object).unwrap(), "archive");
+        assert_eq!(cache.read_index().get(&url), Some(&hash));
+
+        // the cached file is used even if the source is gone
+        remove_file(url.trim_start_matches("file://")).unwrap();
+        assert_eq!(cache.fetch(&url).unwrap(), object);
+
+        // a corrupt file in the cache isn't used
+        let url = source_file(&dir, "source.tar.gz", "archive");
+        write(&object, "corrupt").unwrap();
+        assert_eq!(cache.fetch(&url).unwrap(), object);
+        assert_eq!(read_to_string(&object).unwrap(), "archive");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_rejects_changed_files() {
+        let dir = temporary_dir("cache-mismatch");
+        let cache = Cache::new(&dir, 0);
+        let url = source_file(&dir, "source.tar.gz", "archive");
+        let object = cache.fetch(&url).unwrap();
+
+        // the file changed on the server and the cached file is gone
+        remove_file(&object).unwrap();
+        source_file(&dir, "source.tar.gz", "changed archive");
+        let error = cache.fetch(&url).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("sha256 of {} doesn't match the cache", url)));
+        assert!(!Path::new(&object).exists());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn enforce_limit_removes_old_files_and_their_index_entries() {
+        let dir = temporary_dir("cache-limit");
+        // the limit is 1 MiB --> only one of the files fits
+        let cache = Cache::new(&dir, 1);
+        let content = "x".repeat(700 * 1024);
+        let old_url = source_file(&dir, "old.tar.gz", &format!("old {}", content));
+        let old_object = cache.fetch(&old_url).unwrap();
+        // make sure that the first file is the least recently used one
+        File::options()
+            .write(true)
+            .open(&old_object)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let new_url = source_file(&dir, "new.tar.gz", &format!("new {}", content));
+        let new_object = cache.fetch(&new_url).unwrap();
+
+        assert!(!Path::new(&old_object).exists());
+        assert!(Path::new(&new_object).exists());
+        let index = cache.read_index();
+        assert!(!index.contains_key(&old_url));
+        assert!(index.contains_key(&new_url));
+
+        // the removed file can be downloaded again even if its content changed
+        source_file(&dir, "old.tar.gz", "regenerated archive");
+        assert!(cache.fetch(&old_url).is_ok());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
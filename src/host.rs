@@ -0,0 +1,118 @@
+use std::fs::{read_dir, read_to_string};
+
+// default locations of the properties of the machine
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+
+// struct to contain the properties of the machine that imports can be conditioned on
+#[derive(Debug, Default, Clone)]
+pub struct Host {
+    pub hostname: String,
+    // "intel", "amd" or the lowercase vendor_id of other vendors
+    pub cpu_vendor: String,
+    // vendor:device ids of the pci devices in lowercase hex, e.g. 10de:2484
+    pub pci_devices: Vec<String>,
+}
+
+fn read_hostname() -> String {
+    //! returns the hostname of the kernel or the one in /etc/hostname
+    read_to_string(HOSTNAME_PATH)
+        .or_else(|_| read_to_string("/etc/hostname"))
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn read_cpu_vendor() -> String {
+    //! returns the vendor of the first cpu in /proc/cpuinfo
+    let cpuinfo = read_to_string(CPUINFO_PATH).unwrap_or_default();
+    let vendor_id = cpuinfo
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == "vendor_id").then(|| value.trim().to_string())
+        })
+        .unwrap_or_default();
+
+    match vendor_id.as_str() {
+        "GenuineIntel" => "intel".to_string(),
+        "AuthenticAMD" => "amd".to_string(),
+        _ => vendor_id.to_lowercase(),
+    }
+}
+
+fn read_id(file_path: &str) -> String {
+    //! returns the id in a vendor or device file without the 0x prefix
+    read_to_string(file_path)
+        .unwrap_or_default()
+        .trim()
+        .trim_start_matches("0x")
+        .to_lowercase()
+}
+
+fn read_pci_devices() -> Vec<String> {
+    //! returns the vendor:device ids of all pci devices
+    let Ok(entries) = read_dir(PCI_DEVICES_PATH) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let dir = entry.path().to_string_lossy().to_string();
+            format!(
+                "{}:{}",
+                read_id(&format!("{}/vendor", dir)),
+                read_id(&format!("{}/device", dir))
+            )
+        })
+        .collect()
+}
+
+impl Host {
+    pub fn detect() -> Self {
+        //! reads the properties of the running machine, missing ones are left empty
+        Host {
+            hostname: read_hostname(),
+            cpu_vendor: read_cpu_vendor(),
+            pci_devices: read_pci_devices(),
+        }
+    }
+
+    pub fn has_pci_device(&self, id: &str) -> bool {
+        //! takes a vendor id (10de) or a vendor:device id (10de:2484) and returns true if a
+        //! matching pci device exists
+        let id = id.trim().to_lowercase().replace("0x", "");
+        self.pci_devices.iter().any(|device| {
+            *device == id || (!id.contains(':') && device.split(':').next() == Some(id.as_str()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pci_device_by_vendor_or_vendor_and_device() {
+        let host = Host {
+            hostname: "desktop".to_string(),
+            cpu_vendor: "amd".to_string(),
+            pci_devices: vec!["8086:a0f0".to_string(), "10de:2484".to_string()],
+        };
+        // (id, expected)
+        let cases = [
+            ("10de", true),
+            ("10de:2484", true),
+            ("0x10DE:0x2484", true),
+            (" 10de ", true),
+            ("10de:2485", false),
+            ("1002", false),
+            ("2484", false),
+            ("10d", false),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(host.has_pci_device(id), expected, "{}", id);
+        }
+        assert!(!Host::default().has_pci_device("10de"));
+    }
+}
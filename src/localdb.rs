@@ -0,0 +1,406 @@
+use glob::glob;
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+
+// default location of the pacman database of installed packages
+pub const LOCAL_DB_PATH: &str = "/var/lib/pacman/local";
+
+// reason why a package was installed (%REASON% in the desc file)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InstallReason {
+    #[default]
+    Explicit,
+    Dependency,
+}
+
+// struct to contain the entries of the desc file of an installed package
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    pub base: String,
+    pub reason: InstallReason,
+    pub groups: Vec<String>,
+    pub depends: Vec<String>,
+    pub provides: Vec<String>,
+}
+
+pub fn parse_sections(content: &str) -> HashMap<String, Vec<String>> {
+    //! takes the content of a desc file and returns the values of each section
+    //! the file consists of blocks starting with a %SECTION% line followed by one value per line
+    //! and terminated by an empty line
+    let mut sections: HashMap<String, Vec<String>> = HashMap::new();
+    let mut section = String::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            section.clear();
+            continue;
+        }
+        if line.starts_with('%') && line.ends_with('%') && line.len() > 1 {
+            section = line.trim_matches('%').to_string();
+            sections.entry(section.clone()).or_default();
+            continue;
+        }
+        if !section.is_empty() {
+            sections
+                .entry(section.clone())
+                .or_default()
+                .push(line.to_string());
+        }
+    }
+
+    sections
+}
+
+pub fn first_value(sections: &HashMap<String, Vec<String>>, section: &str) -> String {
+    //! returns the first value of a section or an empty string if the section doesn't exist
+    sections
+        .get(section)
+        .and_then(|values| values.first())
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub fn parse_desc(content: &str) -> InstalledPackage {
+    //! takes the content of a desc file and returns the installed package
+    let sections = parse_sections(content);
+
+    let mut package = InstalledPackage {
+        name: first_value(&sections, "NAME"),
+        version: first_value(&sections, "VERSION"),
+        base: first_value(&sections, "BASE"),
+        groups: sections.get("GROUPS").cloned().unwrap_or_default(),
+        depends: sections.get("DEPENDS").cloned().unwrap_or_default(),
+        provides: sections.get("PROVIDES").cloned().unwrap_or_default(),
+        ..Default::default()
+    };
+
+    if first_value(&sections, "REASON") == "1" {
+        package.reason = InstallReason::Dependency;
+    }
+
+    // split packages without a base use their own name
+    if package.base.is_empty() {
+        package.base = package.name.clone();
+    }
+
+    package
+}
+
+pub fn read_local_db(db_path: &str) -> Result<Vec<InstalledPackage>, Box<dyn std::error::Error>> {
+    //! takes the path to the local database (e.g. /var/lib/pacman/local) and returns all installed
+    //! packages
+    let mut packages: Vec<InstalledPackage> = Vec::new();
+
+    for entry in read_dir(db_path)? {
+        let desc_file = entry?.path().join("desc");
+        if desc_file.is_file() {
+            packages.push(parse_desc(&read_to_string(desc_file)?));
+        }
+    }
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(packages)
+}
+
+pub fn find_installed_package(
+    db_path: &str,
+    package_name: &str,
+) -> Result<Option<InstalledPackage>, Box<dyn std::error::Error>> {
+    //! takes the path to the local database and the package name and returns the installed
+    //! package if it exists
+    //! the package directories are named name-pkgver-pkgrel, so only directories starting with the
+    //! package name are read and the name is checked against the desc file
+    for entry in glob(&format!(
+        "{}/{}-*/desc",
+        db_path,
+        glob::Pattern::escape(package_name)
+    ))? {
+        let desc_file = entry?;
+        let package = parse_desc(&read_to_string(&desc_file)?);
+        if package.name == package_name {
+            return Ok(Some(package));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn find_package_or_base(
+    db_path: &str,
+    package_name: &str,
+) -> Result<Option<InstalledPackage>, Box<dyn std::error::Error>> {
+    //! takes the path to the local database and the package name and returns the installed
+    //! package with the name
+    //! if no package with the name is installed, the first installed package of a split package
+    //! with the name as pkgbase is returned
+    match find_installed_package(db_path, package_name)? {
+        Some(package) => Ok(Some(package)),
+        None => Ok(read_local_db(db_path)?
+            .into_iter()
+            .find(|package| package.base == package_name)),
+    }
+}
+
+fn dependency_name(dependency: &str) -> &str {
+    //! strips the version constraint of a dependency or provision, e.g. glibc>=2.40 or sh=5.2
+    dependency
+        .split(['<', '>', '='])
+        .next()
+        .unwrap_or(dependency)
+}
+
+pub fn dependency_closure(
+    packages: &[InstalledPackage],
+    roots: &[String],
+) -> HashMap<String, String> {
+    //! takes the installed packages and the names of root packages and returns every installed
+    //! root and every package the roots (transitively) depend on, with the root that requires it
+    //! dependencies are resolved by the package name and the provisions of the installed packages
+    let mut closure: HashMap<String, String> = HashMap::new();
+    let mut stack: Vec<(String, String)> = Vec::new();
+
+    for root in roots {
+        if packages.iter().any(|package| &package.name == root) {
+            stack.push((root.clone(), root.clone()));
+        }
+    }
+
+    while let Some((name, root)) = stack.pop() {
+        if closure.contains_key(&name) {
+            continue;
+        }
+        let Some(package) = packages.iter().find(|package| package.name == name) else {
+            continue;
+        };
+        closure.insert(name, root.clone());
+
+        for dependency in &package.depends {
+            let dependency = dependency_name(dependency);
+            if let Some(provider) = packages.iter().find(|package| {
+                package.name == dependency
+                    || package
+                        .provides
+                        .iter()
+                        .any(|provision| dependency_name(provision) == dependency)
+            }) {
+                stack.push((provider.name.clone(), root.clone()));
+            }
+        }
+    }
+
+    closure
+}
+
+fn satisfies(package: &InstalledPackage, dependency: &str) -> bool {
+    //! returns true if the package satisfies the dependency by its name or a provision
+    let dependency = dependency_name(dependency);
+    package.name == dependency
+        || package
+            .provides
+            .iter()
+            .any(|provision| dependency_name(provision) == dependency)
+}
+
+pub fn removal_closure(
+    packages: &[InstalledPackage],
+    targets: &[String],
+) -> HashMap<String, String> {
+    //! takes the installed packages and the targets of pacman -Rs and returns every package that
+    //! would be removed with the target that pulls it in
+    //! like pacman, a dependency is removed with its dependent if it was installed as a dependency
+    //! and no package that stays installed requires it
+    let mut removal: HashMap<String, String> = HashMap::new();
+    for target in targets {
+        if packages.iter().any(|package| &package.name == target) {
+            removal.insert(target.clone(), target.clone());
+        }
+    }
+
+    loop {
+        let mut added: Vec<(String, String)> = Vec::new();
+        for package in packages {
+            if package.reason != InstallReason::Dependency || removal.contains_key(&package.name) {
+                continue;
+            }
+            // a removed package depends on the package
+            let Some(target) = packages
+                .iter()
+                .filter(|dependent| removal.contains_key(&dependent.name))
+                .find(|dependent| {
+                    dependent
+                        .depends
+                        .iter()
+                        .any(|dependency| satisfies(package, dependency))
+                })
+                .map(|dependent| removal[&dependent.name].clone())
+            else {
+                continue;
+            };
+            // no package that stays installed depends on the package
+            let required = packages
+                .iter()
+                .filter(|dependent| !removal.contains_key(&dependent.name))
+                .any(|dependent| {
+                    dependent
+                        .depends
+                        .iter()
+                        .any(|dependency| satisfies(package, dependency))
+                });
+            if !required {
+                added.push((package.name.clone(), target));
+            }
+        }
+        if added.is_empty() {
+            break;
+        }
+        removal.extend(added);
+    }
+
+    removal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/local");
+    const FIXTURE_CLASH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/local-clash");
+
+    #[test]
+    fn parse_desc_reads_all_sections() {
+        let package = parse_desc(
+            "%NAME%\npython-foo\n\n%VERSION%\n1.0-1\n\n%BASE%\nfoo\n\n%GROUPS%\nextras\n\n\
+             %REASON%\n1\n\n%DEPENDS%\npython3>=3.12\nexpat\n\n%PROVIDES%\nfoo=1.0\n\n",
+        );
+        assert_eq!(package.name, "python-foo");
+        assert_eq!(package.version, "1.0-1");
+        assert_eq!(package.base, "foo");
+        assert_eq!(package.reason, InstallReason::Dependency);
+        assert_eq!(package.groups, vec!["extras"]);
+        assert_eq!(package.depends, vec!["python3>=3.12", "expat"]);
+        assert_eq!(package.provides, vec!["foo=1.0"]);
+    }
+
+    #[test]
+    fn parse_desc_defaults() {
+        // without %REASON% the package is explicitly installed, without %BASE% the name is used
+        let package = parse_desc("%NAME%\nexpat\n\n%VERSION%\n2.6.4-1\n\n");
+        assert_eq!(package.reason, InstallReason::Explicit);
+        assert_eq!(package.base, "expat");
+        assert!(package.depends.is_empty());
+    }
+
+    #[test]
+    fn read_local_db_reads_all_packages() {
+        let packages = read_local_db(FIXTURE).unwrap();
+        let names: Vec<&str> = packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        // sorted by name, ALPM_DB_VERSION is skipped
+        assert_eq!(
+            names,
+            vec![
+                "desktop-meta",
+                "expat",
+                "gcc-libs",
+                "grub",
+                "python",
+                "python-foo"
+            ]
+        );
+        assert_eq!(packages[4].version, "3.12.7-1");
+        assert_eq!(packages[4].reason, InstallReason::Explicit);
+        assert_eq!(packages[5].reason, InstallReason::Dependency);
+    }
+
+    #[test]
+    fn read_local_db_missing_directory() {
+        assert!(read_local_db("/nonexistent/pacman/local").is_err());
+    }
+
+    #[test]
+    fn find_installed_package_by_name() {
+        let package = find_installed_package(FIXTURE, "python-foo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(package.version, "1.0-1");
+        assert!(
+            find_installed_package(FIXTURE, "firefox")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn find_installed_package_name_clash() {
+        // the directory python-foo-1.0-1 matches python-*, but it isn't the package python
+        let package = find_installed_package(FIXTURE, "python").unwrap().unwrap();
+        assert_eq!(package.name, "python");
+        assert_eq!(package.version, "3.12.7-1");
+        assert!(
+            find_installed_package(FIXTURE_CLASH, "python")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn find_package_or_base_falls_back_to_pkgbase() {
+        let package = find_package_or_base(FIXTURE, "gcc").unwrap().unwrap();
+        assert_eq!(package.name, "gcc-libs");
+        assert_eq!(package.version, "14.2.1-1");
+        // a package with the name wins over the pkgbase
+        let package = find_package_or_base(FIXTURE, "python").unwrap().unwrap();
+        assert_eq!(package.name, "python");
+        assert!(find_package_or_base(FIXTURE, "firefox").unwrap().is_none());
+    }
+
+    #[test]
+    fn dependency_closure_resolves_provisions() {
+        let packages = read_local_db(FIXTURE).unwrap();
+        let closure = dependency_closure(&packages, &["python-foo".to_string()]);
+        // python-foo depends on python3 which is provided by python
+        assert_eq!(
+            closure.get("python").map(String::as_str),
+            Some("python-foo")
+        );
+        assert_eq!(closure.get("expat").map(String::as_str), Some("python-foo"));
+        assert!(!closure.contains_key("gcc-libs"));
+    }
+
+    #[test]
+    fn removal_closure_follows_dependencies() {
+        let packages = read_local_db(FIXTURE).unwrap();
+
+        // grub was installed as a dependency of desktop-meta and nothing else requires it,
+        // expat is still required by python
+        let removal = removal_closure(&packages, &["desktop-meta".to_string()]);
+        let mut names: Vec<&String> = removal.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["desktop-meta", "grub"]);
+        assert_eq!(removal["grub"], "desktop-meta");
+
+        // removing python as well frees expat
+        let removal = removal_closure(
+            &packages,
+            &["desktop-meta".to_string(), "python".to_string()],
+        );
+        assert_eq!(
+            removal.get("expat").map(String::as_str),
+            Some("desktop-meta")
+        );
+        // dependents of a target aren't removed
+        assert!(!removal.contains_key("python-foo"));
+
+        // explicitly installed dependencies and packages that aren't installed are kept
+        let removal = removal_closure(&packages, &["python-foo".to_string(), "x".to_string()]);
+        let names: Vec<&String> = removal.keys().collect();
+        assert_eq!(names, vec!["python-foo"]);
+    }
+}
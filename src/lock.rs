@@ -0,0 +1,369 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+
+use crate::{
+    Config, get_installed_version, get_upstream_version, get_version_from_local_repo,
+    get_version_from_overlay, read_system_file, resolve_home,
+};
+
+// struct to contain the resolved system state that is written to nompac.lock
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Lockfile {
+    pub snapshot: String,
+    // package name and installed version
+    pub packages: BTreeMap<String, String>,
+    // package name and version that was built (the upstream version it was built from)
+    pub patched_packages: BTreeMap<String, String>,
+    pub overlay_packages: BTreeMap<String, String>,
+    // path of the config file and sha256 of its content
+    pub config_files: BTreeMap<String, String>,
+}
+
+pub fn lockfile_path(path_to_config: &str) -> String {
+    //! takes the path to the config file and returns the path of the lockfile next to it
+    match path_to_config.rsplit_once("/") {
+        Some((dir, _)) => format!("{}/nompac.lock", dir),
+        None => "nompac.lock".to_string(),
+    }
+}
+
+pub fn read_lockfile(file_path: &str) -> Result<Lockfile, Box<dyn std::error::Error>> {
+    //! takes the path to the lockfile and parses it
+    let content = read_to_string(file_path)?;
+    Ok(toml::from_str(&content)?)
+}
+
+pub fn write_lockfile(file_path: &str, lock: &Lockfile) -> Result<(), Box<dyn std::error::Error>> {
+    //! takes the path to the lockfile and the resolved state and writes the lockfile
+    let content = toml::to_string(lock)?;
+    write(file_path, content)?;
+    Ok(())
+}
+
+fn hash_file(file_path: &str, sudo: bool) -> String {
+    //! returns the sha256 of the file content or "none" if the file couldn't be read
+    match read_system_file(file_path, sudo) {
+        Ok(content) => format!("{:x}", Sha256::digest(content.as_bytes())),
+        Err(_) => "none".to_string(),
+    }
+}
+
+fn installed_version(package: &str) -> String {
+    //! returns the installed version of the package or "none" if it isn't installed
+    match get_installed_version(package) {
+        Ok(version) => version.trim().to_string(),
+        Err(_) => "none".to_string(),
+    }
+}
+
+pub fn resolve_state(configs: &Config, snapshot: &str) -> Lockfile {
+    //! collects the current state of the system for all packages and config files managed by the
+    //! config
+    let mut lock = Lockfile {
+        snapshot: snapshot.to_string(),
+        ..Default::default()
+    };
+
+    for package in &configs.packages {
+        lock.packages
+            .insert(package.to_string(), installed_version(package));
+    }
+
+    // the built version is compared with the upstream version before a run, the installed
+    // version can differ from both (e.g. a pending upgrade)
+    for patches in &configs.patches {
+        for package in patches.keys() {
+            let version =
+                get_version_from_local_repo(configs, package).unwrap_or_else(|| "none".to_string());
+            lock.patched_packages.insert(package.to_string(), version);
+        }
+    }
+
+    for package in &configs.overlays {
+        let version = get_version_from_overlay(&configs.overlay_dir, package)
+            .unwrap_or_else(|_| "none".to_string());
+        lock.overlay_packages.insert(package.to_string(), version);
+    }
+
+    for entry in &configs.configs {
+        let file_path_resolved = resolve_home(entry.path.clone());
+        let hash = hash_file(&file_path_resolved, entry.sudo);
+        lock.config_files.insert(file_path_resolved, hash);
+    }
+
+    lock
+}
+
+fn compare_keys(
+    name: &str,
+    locked: &BTreeMap<String, String>,
+    resolved: &BTreeMap<String, String>,
+    differences: &mut Vec<String>,
+) {
+    //! adds a difference for every entry that exists only in one of the two maps
+    for key in locked.keys() {
+        if !resolved.contains_key(key) {
+            differences.push(format!(
+                "{} {} is in the lockfile but not in the config",
+                name, key
+            ));
+        }
+    }
+    for key in resolved.keys() {
+        if !locked.contains_key(key) {
+            differences.push(format!(
+                "{} {} is in the config but not in the lockfile",
+                name, key
+            ));
+        }
+    }
+}
+
+fn compare_values(
+    name: &str,
+    locked: &BTreeMap<String, String>,
+    resolved: &BTreeMap<String, String>,
+    differences: &mut Vec<String>,
+) {
+    //! adds a difference for every entry that exists in both maps with different values
+    for (key, locked_value) in locked {
+        if let Some(resolved_value) = resolved.get(key) {
+            if locked_value != resolved_value {
+                differences.push(format!(
+                    "{} {}: locked {}, resolved {}",
+                    name, key, locked_value, resolved_value
+                ));
+            }
+        }
+    }
+}
+
+pub fn check_before_run(
+    lock: &Lockfile,
+    configs: &Config,
+    snapshot: &str,
+    check_builds: bool,
+) -> Vec<String> {
+    //! checks if the run would resolve to a different state than the lockfile
+    //! only compares what can be known before the system is changed: snapshot, declared packages,
+    //! versions of packages that would be built and the set of config files
+    //! returns the list of differences
+    let mut differences: Vec<String> = Vec::new();
+
+    if lock.snapshot != snapshot {
+        differences.push(format!(
+            "snapshot: locked {}, resolved {}",
+            lock.snapshot, snapshot
+        ));
+    }
+
+    let mut packages: BTreeMap<String, String> = BTreeMap::new();
+    for package in &configs.packages {
+        packages.insert(package.to_string(), String::new());
+    }
+    compare_keys("package", &lock.packages, &packages, &mut differences);
+
+    let mut config_files: BTreeMap<String, String> = BTreeMap::new();
+    for entry in &configs.configs {
+        config_files.insert(resolve_home(entry.path.clone()), String::new());
+    }
+    compare_keys(
+        "config file",
+        &lock.config_files,
+        &config_files,
+        &mut differences,
+    );
+
+    let mut overlay_packages: BTreeMap<String, String> = BTreeMap::new();
+    for package in &configs.overlays {
+        let version = get_version_from_overlay(&configs.overlay_dir, package)
+            .unwrap_or_else(|_| "none".to_string());
+        overlay_packages.insert(package.to_string(), version);
+    }
+    compare_keys(
+        "overlay",
+        &lock.overlay_packages,
+        &overlay_packages,
+        &mut differences,
+    );
+    compare_values(
+        "overlay",
+        &lock.overlay_packages,
+        &overlay_packages,
+        &mut differences,
+    );
+
+    let mut patched_packages: BTreeMap<String, String> = BTreeMap::new();
+    for patches in &configs.patches {
+        for package in patches.keys() {
+            // the upstream version is the version that the build phase builds, it is only
+            // fetched if packages would be built
+            let version = if check_builds {
+                get_upstream_version(configs, package)
+                    .map(|version| version.trim().to_string())
+                    .unwrap_or_else(|_| "none".to_string())
+            } else {
+                lock.patched_packages
+                    .get(package)
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            patched_packages.insert(package.to_string(), version);
+        }
+    }
+    compare_keys(
+        "patched package",
+        &lock.patched_packages,
+        &patched_packages,
+        &mut differences,
+    );
+    compare_values(
+        "patched package",
+        &lock.patched_packages,
+        &patched_packages,
+        &mut differences,
+    );
+
+    differences
+}
+
+pub fn compare_state(lock: &Lockfile, resolved: &Lockfile) -> Vec<String> {
+    //! compares the lockfile with the state of the system after a run and returns the list of
+    //! differences
+    let mut differences: Vec<String> = Vec::new();
+
+    if lock.snapshot != resolved.snapshot {
+        differences.push(format!(
+            "snapshot: locked {}, resolved {}",
+            lock.snapshot, resolved.snapshot
+        ));
+    }
+
+    for (name, locked, current) in [
+        ("package", &lock.packages, &resolved.packages),
+        (
+            "patched package",
+            &lock.patched_packages,
+            &resolved.patched_packages,
+        ),
+        (
+            "overlay",
+            &lock.overlay_packages,
+            &resolved.overlay_packages,
+        ),
+        ("config file", &lock.config_files, &resolved.config_files),
+    ] {
+        compare_keys(name, locked, current, &mut differences);
+        compare_values(name, locked, current, &mut differences);
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(packages: &str, overlays: &str, configs: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            name = "test"
+            build_dir = "/tmp"
+            patch_dir = "/tmp"
+            overlay_dir = "{}/tests/fixtures/pkgbuild"
+            local_repo = "none"
+            packages = [{}]
+            patches = [{{ gcc-libs = ["0001-fix.patch"] }}]
+            overlays = [{}]
+            pacconfig = "/etc/pacman.conf"
+            mirrorlist = "/etc/pacman.d/mirrorlist"
+            snapshot = "none"
+            configs = [{}]
+            imports = []
+            "#,
+            env!("CARGO_MANIFEST_DIR"),
+            packages,
+            overlays,
+            configs
+        ))
+        .unwrap()
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn lockfile() -> Lockfile {
+        Lockfile {
+            snapshot: "2024_11_01".to_string(),
+            packages: entries(&[("glibc", "2.40-1"), ("vim", "9.1-1")]),
+            patched_packages: entries(&[("gcc-libs", "14.2.1-1")]),
+            overlay_packages: entries(&[("nano", "8.2-1")]),
+            config_files: entries(&[("/etc/pacman.conf", "abc")]),
+        }
+    }
+
+    #[test]
+    fn check_before_run_without_differences() {
+        let configs = config(
+            r#""glibc", "vim""#,
+            r#""nano""#,
+            r#"{ path = "/etc/pacman.conf", sudo = false, config_entry = [] }"#,
+        );
+        // the overlay version is read from the PKGBUILD of the fixture
+        assert!(check_before_run(&lockfile(), &configs, "2024_11_01", false).is_empty());
+    }
+
+    #[test]
+    fn check_before_run_reports_differences() {
+        let configs = config(
+            r#""glibc", "firefox""#,
+            r#""nano""#,
+            r#"{ path = "/etc/makepkg.conf", sudo = false, config_entry = [] }"#,
+        );
+        let mut lock = lockfile();
+        lock.overlay_packages = entries(&[("nano", "8.1-1")]);
+
+        assert_eq!(
+            check_before_run(&lock, &configs, "2024_12_01", false),
+            vec![
+                "snapshot: locked 2024_11_01, resolved 2024_12_01".to_string(),
+                "package vim is in the lockfile but not in the config".to_string(),
+                "package firefox is in the config but not in the lockfile".to_string(),
+                "config file /etc/pacman.conf is in the lockfile but not in the config".to_string(),
+                "config file /etc/makepkg.conf is in the config but not in the lockfile"
+                    .to_string(),
+                "overlay nano: locked 8.1-1, resolved 8.2-1".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_state_of_two_lockfiles() {
+        let lock = lockfile();
+        assert!(compare_state(&lock, &lockfile()).is_empty());
+
+        let mut resolved = lockfile();
+        resolved
+            .packages
+            .insert("glibc".to_string(), "2.41-1".to_string());
+        resolved.patched_packages.clear();
+        resolved
+            .config_files
+            .insert("/etc/pacman.conf".to_string(), "def".to_string());
+
+        assert_eq!(
+            compare_state(&lock, &resolved),
+            vec![
+                "package glibc: locked 2.40-1, resolved 2.41-1",
+                "patched package gcc-libs is in the lockfile but not in the config",
+                "config file /etc/pacman.conf: locked abc, resolved def",
+            ]
+        );
+    }
+}
@@ -1,18 +1,15 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
 use dirs::home_dir;
-use flate2::read::GzDecoder;
 use glob::glob;
 use regex::Regex;
-use reqwest;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::{File, copy, read_to_string, write};
-use std::io::{BufRead, BufReader, Write, stdin};
-use std::ops::Index;
+use std::io::{BufRead, BufReader, stdin};
 use std::path::Path;
 use std::process::{Command, Output, Stdio};
-use tar::Archive;
 use toml::{self, Table};
 use walkdir::WalkDir;
 
@@ -20,31 +17,163 @@ use walkdir::WalkDir;
 use std::sync::mpsc::{self, Sender};
 use std::thread;
 
-// Search for a pattern in a file and display the lines that contain it.
+mod builder;
+mod cache;
+mod host;
+mod localdb;
+mod lock;
+mod patch;
+mod pkgbuild;
+mod repo;
+mod source;
+mod syncdb;
+mod validate;
+mod vercmp;
+
+use builder::{BuildJob, BuildReport, BuildResult, Chroot};
+use cache::Cache;
+use host::Host;
+use patch::{PackagePatches, PatchConfig, PatchEntry};
+
+// command line arguments that are valid for all subcommands
 #[derive(Parser)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     #[clap(
         long = "snapshot",
         short = 's',
         default_value = "none",
+        global = true,
         help = "Defines the date of the Arch-repository snapshot that should be used. Always enter in the format YYYY_MM_DD. If no date is entered, no update will be performed."
     )]
     snapshot: String,
 
-    #[clap(long = "pacconfig", short = 'p', default_value = "none")]
+    #[clap(long = "pacconfig", short = 'p', default_value = "none", global = true)]
     pacconfig: String,
 
     #[clap(
         long = "config",
         short = 'c',
-        default_value = "~/.config/nompac/configs/config.toml"
+        default_value = "~/.config/nompac/configs/config.toml",
+        global = true
     )]
     config: String,
 
-    #[clap(long = "initiate", short = 'i', default_value = "no")]
-    initiate: String,
+    #[clap(
+        long = "dry-run",
+        short = 'n',
+        global = true,
+        help = "Only print the planned removals, installations, builds and file changes. Nothing will be executed."
+    )]
+    dry_run: bool,
+
+    #[clap(
+        long = "locked",
+        global = true,
+        help = "Refuse to proceed if the resolved system state differs from nompac.lock next to the config file."
+    )]
+    locked: bool,
+
+    #[clap(
+        long = "demote",
+        global = true,
+        help = "Mark undeclared packages as dependencies instead of removing them."
+    )]
+    demote: bool,
+
+    #[clap(
+        long = "profile",
+        default_value = "none",
+        global = true,
+        help = "Selects the profile for the imports that are conditioned on a profile."
+    )]
+    profile: String,
+}
+
+// phases of a run that can be executed separately
+#[derive(Subcommand, Clone, PartialEq)]
+enum Commands {
+    /// Run all phases: build, update, configs and clean (default)
+    Sync,
+    /// Build patched upstream-packages and overlays and add them to the local repository
+    Build,
+    /// Apply the changes to config files
+    Configs,
+    /// Pin the snapshot, install and remove packages and update the system
+    Update,
+    /// Remove orphaned packages, clean the package cache and show failed daemons
+    Clean,
+    /// Create the local repository and add it to pacman.conf
+    Init,
+    /// Show the difference between the config and the system
+    Status,
+    /// Show the latest build log of a package
+    Logs {
+        package: String,
+        #[clap(
+            long = "diff",
+            help = "Show the differences between the last successful and the latest build log."
+        )]
+        diff: bool,
+    },
+    /// Manage the packages of the local repositories
+    Repo {
+        #[command(subcommand)]
+        action: RepoCommands,
+    },
+}
+
+// actions of the repo command
+#[derive(Subcommand, Clone, PartialEq)]
+enum RepoCommands {
+    /// List the packages of the local repositories
+    List,
+    /// Remove a package (or all packages of a pkgbase) from the local repositories
+    Remove { package: String },
+    /// Remove old versions of the packages from the local repositories
+    Prune {
+        #[clap(
+            long = "keep",
+            default_value_t = 1,
+            help = "Number of versions per package that are kept."
+        )]
+        keep: usize,
+        #[clap(
+            long = "undeclared",
+            help = "Also remove packages that aren't declared as patched packages or overlays."
+        )]
+        undeclared: bool,
+    },
+    /// Check the package files, checksums and signatures of the local repositories
+    Verify,
+}
+
+impl Commands {
+    fn includes(&self, phase: &Commands) -> bool {
+        //! returns true if the phase is executed as part of the command
+        *self == Commands::Sync || self == phase
+    }
 }
 
+// packages that are always protected from removal: base system, kernels, firmware, bootloaders
+// and pacman itself
+const DEFAULT_PROTECTED: [&str; 12] = [
+    "base",
+    "linux",
+    "linux-lts",
+    "linux-zen",
+    "linux-hardened",
+    "linux-firmware",
+    "grub",
+    "efibootmgr",
+    "refind",
+    "limine",
+    "systemd",
+    "pacman",
+];
+
 // struct to contain all config settings
 #[derive(Debug, Deserialize, Serialize)]
 struct Config {
@@ -53,15 +182,214 @@ struct Config {
     patch_dir: String,
     overlay_dir: String,
     local_repo: String,
+    // name of the local repository, defaults to the name of the db-file in local_repo
+    #[serde(default)]
+    local_repo_name: String,
+    // additional local repositories, e.g. to keep patched packages and overlays apart
+    #[serde(default)]
+    local_repos: Vec<LocalRepo>,
     packages: Vec<String>,
-    package_groups: Vec<String>,
-    patches: Vec<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    package_groups: Vec<PackageGroup>,
+    patches: Vec<HashMap<String, PackagePatches>>,
     overlays: Vec<String>,
     pacconfig: String,
     mirrorlist: String,
     snapshot: String,
     configs: Vec<SystemConfigs>,
-    imports: Vec<String>,
+    imports: Vec<Import>,
+    // "host" builds with makepkg on the system, "chroot" builds in a clean chroot
+    #[serde(default = "default_build_mode")]
+    build_mode: String,
+    // directory of the clean chroot, defaults to build_dir/chroot
+    #[serde(default)]
+    chroot_dir: String,
+    // number of packages that are built at the same time (only with build_mode chroot)
+    #[serde(default = "default_max_parallel_builds")]
+    max_parallel_builds: usize,
+    // maximum size of the download cache in MiB, 0 for no limit
+    #[serde(default = "default_cache_size")]
+    cache_size: u64,
+    // packages that are never removed in addition to the built-in ones
+    #[serde(default)]
+    protected: Vec<String>,
+    // removals of more packages have to be confirmed
+    #[serde(default = "default_max_removals")]
+    max_removals: usize,
+    // undeclared packages are marked as dependencies instead of removed, the orphan cleanup
+    // removes the ones that nothing depends on
+    #[serde(default)]
+    demote: bool,
+    // gpg key that signs the built packages and the local repositories, empty for no signing
+    #[serde(default)]
+    signing_key: String,
+    // file that declares a package, group or overlay, keyed by kind and name
+    #[serde(skip)]
+    origins: HashMap<(String, String), String>,
+    // sync databases of the snapshot (or of the system without a snapshot) in the order of
+    // pacman.conf, read once per run
+    #[serde(skip)]
+    sync_dbs: Vec<syncdb::SyncDb>,
+}
+
+impl Config {
+    fn record_origins(&mut self, file_path: &str) {
+        //! marks all packages, groups and overlays that have no origin yet as declared in the file
+        let declared = self
+            .packages
+            .iter()
+            .map(|name| ("package", name.as_str()))
+            .chain(
+                self.package_groups
+                    .iter()
+                    .map(|group| ("group", group.name())),
+            )
+            .chain(self.overlays.iter().map(|name| ("overlay", name.as_str())));
+        for (kind, name) in declared {
+            self.origins
+                .entry((kind.to_string(), name.to_string()))
+                .or_insert(file_path.to_string());
+        }
+    }
+
+    fn declared_in(&self, kind: &str, name: &str) -> String {
+        //! returns the file that declares the package, group or overlay
+        self.origins
+            .get(&(kind.to_string(), name.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+// struct to contain a local repository that built packages are added to
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct LocalRepo {
+    // db.tar.zst-file of the repository or the directory containing it
+    path: String,
+    // name of the repository in pacman.conf, defaults to the name of the db-file
+    #[serde(default)]
+    name: String,
+    // "patched" or "overlays" to only add these packages, empty for all packages
+    #[serde(default)]
+    packages: String,
+}
+
+impl LocalRepo {
+    fn dir(&self) -> String {
+        //! returns the directory of the repository
+        match self.path.rsplit_once("/") {
+            Some((dir, _)) => dir.to_string(),
+            None => ".".to_string(),
+        }
+    }
+}
+
+fn default_build_mode() -> String {
+    "host".to_string()
+}
+
+fn default_max_parallel_builds() -> usize {
+    1
+}
+
+fn default_max_removals() -> usize {
+    10
+}
+
+fn default_cache_size() -> u64 {
+    4096
+}
+
+// imported config file, either the path or a table with conditions that all have to be met
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Import {
+    Path(String),
+    Conditional(ConditionalImport),
+}
+
+// conditions of an import, empty conditions are always met
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConditionalImport {
+    path: String,
+    #[serde(default)]
+    hostname: String,
+    // "intel", "amd" or the vendor_id of /proc/cpuinfo
+    #[serde(default)]
+    cpu_vendor: String,
+    // vendor id (10de) or vendor:device id (10de:2484) of a pci device
+    #[serde(default)]
+    pci_device: String,
+    // profile selected with --profile
+    #[serde(default)]
+    profile: String,
+}
+
+impl Import {
+    fn path(&self) -> &str {
+        match self {
+            Import::Path(path) => path,
+            Import::Conditional(import) => &import.path,
+        }
+    }
+
+    fn conditions(&self) -> Vec<String> {
+        //! returns the conditions of the import for the output
+        let Import::Conditional(import) = self else {
+            return Vec::new();
+        };
+        [
+            ("hostname", &import.hostname),
+            ("cpu_vendor", &import.cpu_vendor),
+            ("pci_device", &import.pci_device),
+            ("profile", &import.profile),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(condition, value)| format!("{} = {}", condition, value))
+        .collect()
+    }
+
+    fn applies(&self, host: &Host, profile: &str) -> bool {
+        //! returns true if all conditions of the import are met on the host
+        let Import::Conditional(import) = self else {
+            return true;
+        };
+        (import.hostname.is_empty() || import.hostname == host.hostname)
+            && (import.cpu_vendor.is_empty()
+                || import.cpu_vendor.eq_ignore_ascii_case(&host.cpu_vendor))
+            && (import.pci_device.is_empty() || host.has_pci_device(&import.pci_device))
+            && (import.profile.is_empty() || import.profile == profile)
+    }
+}
+
+// package group in the config, either the name or a table with members that are excluded
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum PackageGroup {
+    Name(String),
+    Table {
+        name: String,
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+}
+
+impl PackageGroup {
+    fn name(&self) -> &str {
+        match self {
+            PackageGroup::Name(name) => name,
+            PackageGroup::Table { name, .. } => name,
+        }
+    }
+
+    fn exclude(&self) -> &[String] {
+        match self {
+            PackageGroup::Name(_) => &[],
+            PackageGroup::Table { exclude, .. } => exclude,
+        }
+    }
 }
 
 // structs to contain required changed to config files
@@ -85,7 +413,7 @@ impl Clone for SystemConfigs {
     fn clone(&self) -> Self {
         SystemConfigs {
             path: self.path.clone(),
-            sudo: self.sudo.clone(),
+            sudo: self.sudo,
             config_entry: self.config_entry.clone(),
         }
     }
@@ -104,201 +432,399 @@ impl Clone for ConfigEntry {
     }
 }
 
-fn get_current_version_from_repo(package_name: &str) -> Result<String, Box<dyn std::error::Error>> {
-    //! read current package version from repository
-    //! takes package name and returns version-revision
-    let url = format!(
-        "https://gitlab.archlinux.org/archlinux/packaging/packages/{}/-/raw/main/PKGBUILD",
-        package_name
-    );
-    // is the url accessable?
-    let response = reqwest::blocking::get(url)?;
-    // read the response body as string
-    let body = response.text()?;
-    get_version_from_pkgbuild(&body)
+fn repository_for<'a>(configs: &'a Config, packages: &str) -> Option<&'a LocalRepo> {
+    //! takes the kind of a built package ("patched" or "overlays") and returns the local repository
+    //! it is added to
+    //! a repository that is configured for the kind is preferred over one for all packages
+    configs
+        .local_repos
+        .iter()
+        .find(|repository| repository.packages == packages)
+        .or_else(|| {
+            configs
+                .local_repos
+                .iter()
+                .find(|repository| repository.packages.is_empty())
+        })
 }
 
-fn get_version_from_overlay(
-    overlay_dir: &str,
-    package_name: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    //! takes overlay directory and package name and returns version-revision
-    let url = format!("{}/{}/PKGBUILD", overlay_dir, package_name);
-    let contents = read_to_string(url)?;
-    get_version_from_pkgbuild(&contents)
+fn snapshot_server(date: &[String]) -> String {
+    //! returns the server of the snapshot of the official repositories at the date
+    format!(
+        "https://archive.archlinux.org/repos/{}/{}/{}/$repo/os/$arch",
+        date[0], date[1], date[2]
+    )
 }
 
-fn get_version_from_pkgbuild(file_contents: &str) -> Result<String, Box<dyn std::error::Error>> {
-    //! Extract version from pkgbuild-file that was given as string in file_contents
-
-    let mut pkgver_row: Vec<&str> = Vec::new();
-    for line in file_contents.lines() {
-        if line.starts_with("pkgver=") {
-            pkgver_row.append(&mut line.split("=").collect());
-        }
-        if line.starts_with("pkgrel=") {
-            pkgver_row.append(&mut line.split("=").collect());
+fn read_sync_databases(configs: &Config, date: &[String]) -> Result<Vec<syncdb::SyncDb>, String> {
+    //! returns the sync databases of the repositories in pacman.conf in the order of pacman.conf
+    //! with a snapshot date, the databases are downloaded from the snapshot without changing the
+    //! system, so every phase sees the packages of the snapshot before the system is refreshed
+    //! the local repositories and repositories that aren't part of the snapshot are read from
+    //! the sync directory of the system
+    let pacman_conf = read_to_string(&configs.pacconfig)
+        .map_err(|e| format!("{} couldn't be read: {}", configs.pacconfig, e))?;
+    let repositories = syncdb::configured_repositories(&pacman_conf);
+
+    let mut databases: Vec<syncdb::SyncDb> = Vec::new();
+    for repository in &repositories {
+        let local = configs
+            .local_repos
+            .iter()
+            .any(|local_repo| &local_repo.name == repository);
+        if date[0] != "none" && !local {
+            match syncdb::download_sync_db(&snapshot_server(date), repository) {
+                Ok(database) => {
+                    databases.push(database);
+                    continue;
+                }
+                Err(e) => println!(
+                    "{}",
+                    format!(
+                        "Database of [{}] couldn't be downloaded from the snapshot --> using the one of the system: {}",
+                        repository, e
+                    )
+                    .yellow()
+                ),
+            }
         }
+        databases.extend(syncdb::read_sync_dbs(
+            syncdb::SYNC_DB_PATH,
+            std::slice::from_ref(repository),
+        ));
     }
 
-    if pkgver_row.is_empty() {
-        pkgver_row.push("pkgver");
-        pkgver_row.push("0");
-        pkgver_row.push("pkgrel");
-        pkgver_row.push("0");
+    if databases.is_empty() {
+        return Err(format!(
+            "No sync database of the repositories in {} could be read",
+            configs.pacconfig
+        ));
     }
-
-    Ok(format!(
-        "{}-{}",
-        pkgver_row[1].to_string(),
-        pkgver_row[3].to_string()
-    ))
+    Ok(databases)
 }
 
-fn get_current_tarball_from_repo(
+fn get_current_version_from_repo(
+    configs: &Config,
     package_name: &str,
-    package_version: &str,
-    file_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    //! fetch the tarball from arch online repository.
-    //! parameters: package_name, package_version, file_path
-
-    let url = format!(
-        "https://gitlab.archlinux.org/archlinux/packaging/packages/{}/-/archive/{}/{}.tar.gz",
-        package_name,
-        package_version,
-        format!("{}-{}", package_name, package_version)
-    );
+) -> Result<String, Box<dyn std::error::Error>> {
+    //! read current package version from the sync databases of the official repositories
+    //! takes package name and returns epoch:version-revision
+    //! the local repositories are skipped since they contain the patched packages
+    let local_repositories: Vec<String> = configs
+        .local_repos
+        .iter()
+        .map(|repository| repository.name.clone())
+        .collect();
+    if configs.sync_dbs.is_empty() {
+        return Err("The sync databases couldn't be read".into());
+    }
 
-    let resp = reqwest::blocking::get(&url)?;
+    match syncdb::find_repo_package(&configs.sync_dbs, package_name, &local_repositories) {
+        Some(package) => Ok(package.version.clone()),
+        None => Err(format!("Package {} not found in the sync databases", package_name).into()),
+    }
+}
 
-    let mut out_file = std::fs::File::create(file_path)?;
-    let body = resp.bytes()?;
-    out_file.write_all(&body)?;
+fn get_version_from_local_repo(configs: &Config, package_name: &str) -> Option<String> {
+    //! takes the config struct and the package name and returns the version of the package in the
+    //! local repository if it was already built
+    for repository in &configs.local_repos {
+        match syncdb::read_sync_db(&repository.path) {
+            Ok(sync_db) => {
+                if let Some(package) = syncdb::find_repo_package(&[sync_db], package_name, &[]) {
+                    return Some(package.version.clone());
+                }
+            }
+            Err(e) => eprintln!(
+                "Local repository {} couldn't be read: {}",
+                repository.path, e
+            ),
+        }
+    }
+    None
+}
 
-    println!(
-        "Successfully downloaded {}-{}.tar.gz",
-        package_name, package_version
-    );
+fn get_version_from_overlay(
+    overlay_dir: &str,
+    package_name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    //! takes overlay directory and package name and returns epoch:version-revision
+    Ok(pkgbuild::read_package_info(&format!("{}/{}", overlay_dir, package_name))?.version())
+}
 
-    Ok(())
+fn package_patches<'a>(configs: &'a Config, package: &str) -> Option<&'a PackagePatches> {
+    //! returns the patches and the source of a patched package
+    configs
+        .patches
+        .iter()
+        .find_map(|patches| patches.get(package))
 }
 
-fn extract_tgz(filename: &str, output_path: &str) -> Result<(), std::io::Error> {
-    //! takes filename to be extracted and the output path
-    //! extract a tar.gz file
-    let file = File::open(filename)?;
-    let reader = BufReader::new(GzDecoder::new(file));
+fn patched_package_names(configs: &Config) -> Vec<&String> {
+    //! returns the sorted names of the patched packages of the config and all imports
+    let mut packages: Vec<&String> = configs
+        .patches
+        .iter()
+        .flat_map(|patches| patches.keys())
+        .collect();
+    packages.sort();
+    packages.dedup();
+    packages
+}
 
-    let mut archive = Archive::new(reader);
+fn get_upstream_version(
+    configs: &Config,
+    package_name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    //! takes the name of a patched package and returns the version (epoch:version-revision) that
+    //! the source of the package provides
+    let source = package_patches(configs, package_name)
+        .map(PackagePatches::source)
+        .unwrap_or_default();
+    source::backend(configs, &source).latest_version(package_name)
+}
 
-    archive.unpack(output_path)?;
+fn check_patched_package(configs: &Config, package: &str, dry_run: bool) -> Option<String> {
+    //! takes the name of a patched package and compares the installed version with the version of
+    //! its source (by default the official repository)
+    //! returns the upstream version if the package needs to be rebuilt
+    //! in a dry run, git and AUR sources aren't cloned into the cache, so the version is
+    //! "unknown" if it can't be determined without a download
+    let mut package_version_installed: String = String::new();
+
+    let upstream_version = if dry_run {
+        let source = package_patches(configs, package)
+            .map(PackagePatches::source)
+            .unwrap_or_default();
+        source::backend(configs, &source).known_version(package)
+    } else {
+        get_upstream_version(configs, package).map(Some)
+    };
+    let package_version_repo = match upstream_version {
+        Ok(Some(version)) => version,
+        Ok(None) => return Some("unknown".to_string()),
+        Err(e) => {
+            println!(
+                "{}",
+                format!(
+                    "Upstream version of package {} couldn't be determined: {}",
+                    package, e
+                )
+                .red()
+            );
+            return None;
+        }
+    };
+    match get_installed_version(package) {
+        Ok(version) => package_version_installed = version,
+        Err(e) => println!(
+            "{}",
+            format!(
+                "Package version of installed package {} couldn't be determined: {}",
+                package, e
+            )
+            .red()
+        ),
+    }
 
-    Ok(())
+    match vercmp::vercmp(
+        package_version_repo.trim(),
+        package_version_installed.trim(),
+    ) {
+        Ordering::Greater => Some(package_version_repo.trim().to_string()),
+        Ordering::Less => {
+            println!(
+                "{}",
+                format!(
+                    "Installed version {} of package {} is newer than the repository version {} --> no rebuild.",
+                    package_version_installed.trim(),
+                    package,
+                    package_version_repo.trim()
+                )
+                .blue()
+            );
+            None
+        }
+        Ordering::Equal => None,
+    }
 }
 
-fn modify_pkgbuild(file: &str, patch: &str, package_name: &str) -> Result<(), std::io::Error> {
-    //! takes PKBBUILD file and patchname and adds the patch to the file
-    let mut block_state = format!("{}", "none");
-    let mut prepare_block_exists = false;
+fn check_overlay_package(configs: &Config, package: &str) -> Option<String> {
+    //! takes the config struct and the name of an overlay package and compares the installed
+    //! version with the version in the overlay and in the local repository
+    //! returns the version from the overlay if the package needs to be built
+    let mut package_version_installed: String = "0".to_string();
 
-    // Read the contents of the file into a String
-    let content = read_to_string(file)?;
-    // Initialize modified_content as an empty String
-    let mut modified_content: String = String::new();
-
-    let mut lines = content.lines();
+    match get_installed_version(package) {
+        Ok(version) => package_version_installed = version,
+        Err(e) => println!(
+            "{}",
+            format!(
+                "Package version of installed package {} couldn't be determined: {}",
+                package, e
+            )
+            .red()
+        ),
+    }
 
-    while let Some(line) = lines.next() {
-        if line.trim_start().starts_with("source") {
-            block_state = format!("{}", "source");
-        }
-        if line.trim_start().starts_with("prepare") {
-            block_state = format!("{}", "prepare");
-            prepare_block_exists = true;
-        }
-        if block_state == "source" && line.trim_start().ends_with(")") {
-            block_state = format!("{}", "none");
-            modified_content.push_str(&format!("    \"{}\"\n", patch));
+    let package_version_overlay = match get_version_from_overlay(&configs.overlay_dir, package) {
+        Ok(version) => version,
+        Err(e) => {
+            println!(
+                "{}",
+                format!(
+                    "Package version of package {} from overlay couldn't be determined: {}",
+                    package, e
+                )
+                .red()
+            );
+            return None;
         }
-        if block_state == "prepare" && line.trim_start().ends_with("}") {
-            block_state = format!("{}", "none");
-            modified_content.push_str(&format!("    patch -Np1 -i \"${{srcdir}}/{}\"\n", patch));
+    };
+
+    // check if the package exists in the official repos and is newer
+    if let Ok(package_version_repo) = get_current_version_from_repo(configs, package) {
+        if vercmp::vercmp(package_version_repo.trim(), package_version_overlay.trim())
+            != Ordering::Less
+        {
+            println!("{}", format!("Package {} has the same or a newer version in official repo --> consider updating the version or remove the package from the overlay.", package).blue());
         }
-        modified_content.push_str(&format!("{}\n", line));
     }
 
-    // if no prepare block exists in the PKGBUILD, append the block with the patch command
-    if prepare_block_exists == false {
-        modified_content.push_str(&format!("\nprepare() {{\n    cd {package_name}-\"${{pkgver}}\"\n    patch -Np1 -i \"${{srcdir}}/{}\"\n}}\n", patch));
+    // a downgrade to an older overlay version is never built automatically
+    if vercmp::vercmp(
+        package_version_installed.trim(),
+        package_version_overlay.trim(),
+    ) != Ordering::Less
+    {
+        return None;
     }
 
-    // Write the entire modified content back to the file in one step
-    write(file, &modified_content)?;
-
-    Ok(())
+    // first check if the package was alread build and is available in the local repo
+    match get_version_from_local_repo(configs, package) {
+        Some(version)
+            if vercmp::vercmp(version.trim(), package_version_overlay.trim()) != Ordering::Less =>
+        {
+            None
+        }
+        _ => Some(package_version_overlay.trim().to_string()),
+    }
 }
 
-fn apply_patches(config: &Config, patches: &Vec<String>, packagename: &str, packageversion: &str) {
-    //! funtion takes the configuration, a vector of packages, the package name for that the and
-    //! the package version
-    //! patches should be applied and the path to the PKBBUILD file.
-    //! Then the function modifies the PKGBUILD file.
-    for patch in patches {
-        let pkg_build_dir = format!(
-            "{}/src/{}-{}/",
-            config.build_dir, packagename, packageversion
-        );
+fn apply_patches(
+    config: &Config,
+    patches: &[PatchConfig],
+    packagename: &str,
+    packageversion: &str,
+    chroot: Option<&Chroot>,
+) -> Option<Vec<PatchEntry>> {
+    //! funtion takes the configuration, the patches from the config, the package name for that the
+    //! patches should be applied and the package version.
+    //! Then the function copies the patches whose condition matches the version to the build
+    //! directory, checks that all patches apply and modifies the PKGBUILD file.
+    //! returns the applied patches or None if a patch doesn't apply and the package shouldn't be
+    //! built
+    let pkg_build_dir = format!(
+        "{}/src/{}-{}/",
+        config.build_dir,
+        packagename,
+        packageversion.replace(':', "-")
+    );
+    let patch_entries = patch::select_patches(patches, packageversion);
+
+    for patch in &patch_entries {
         let _ = copy(
-            format!("{}/{}/{}", config.patch_dir, packagename, patch),
-            format!("{}/{}", pkg_build_dir, patch),
+            format!("{}/{}/{}", config.patch_dir, packagename, patch.file),
+            format!("{}/{}", pkg_build_dir, patch.file),
         );
-        let _ = modify_pkgbuild(&format!("{}/PKGBUILD", pkg_build_dir), &patch, packagename);
     }
-}
 
-fn build_package(pkg_build_dir: &str) {
-    //! takes the src-directory of the build files and executes a bash process to
-    //! build the package
+    // check that the patches apply before the package is built
+    let srcdest = Cache::new(&config.build_dir, config.cache_size).srcdest();
+    match patch::preflight(
+        packagename,
+        &pkg_build_dir,
+        &patch_entries,
+        &srcdest,
+        chroot,
+    ) {
+        Ok(reports) => {
+            patch::print_report(packagename, &reports);
+            if reports.iter().any(|report| !report.status.applies()) {
+                return None;
+            }
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                format!("Patches of {} couldn't be checked: {}", packagename, e).red()
+            );
+            return None;
+        }
+    }
 
-    let commands: Vec<String> = vec![
-        format!("pushd {}", pkg_build_dir),
-        "updpkgsums".to_string(),
-        "makepkg -cCsr --skippgpcheck".to_string(),
-        "popd".to_string(),
-    ];
+    if let Err(e) = patch::modify_pkgbuild(&format!("{}/PKGBUILD", pkg_build_dir), &patch_entries) {
+        println!(
+            "{}",
+            format!("PKGBUILD of {} couldn't be modified: {}", packagename, e).red()
+        );
+        return None;
+    }
 
-    create_cmd_thread(commands, true);
+    Some(patch_entries)
 }
 
 fn update_repository(
-    config: &Config,
-    local_repo_dir: &str,
-    packagename: &str,
+    repository: &LocalRepo,
+    pkg_build_dir: &str,
+    signing_key: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    //! takes config struct and packagename and updates the repository so that a build package is
-    //! copied to the local repository directory and added to the directory
-    for entry_result in glob(&format!(
-        "{}/src/{}*/**/*.pkg.tar.zst",
-        config.build_dir, packagename
-    ))? {
+    //! takes the local repository and the src-directory of the build files and updates the
+    //! repository so that all built packages (including split packages) are copied to the
+    //! repository directory and added to the database
+    //! with a signing key, the packages and the database are signed and the signatures of the
+    //! packages are verified before they are added
+    let local_repo_dir = repository.dir();
+    for entry_result in glob(&format!("{}/*.pkg.tar.zst", pkg_build_dir))? {
         match entry_result {
             Ok(entry) => {
                 if let Some(file_name) = entry.as_path().file_name() {
-                    let _ = copy(
-                        entry.as_path(),
-                        &format!("{}/{}", local_repo_dir, file_name.to_string_lossy()),
+                    let package_file =
+                        format!("{}/{}", local_repo_dir, file_name.to_string_lossy());
+                    copy(entry.as_path(), &package_file)?;
+
+                    let mut commands: Vec<String> = Vec::new();
+                    let (key, database, package) = (
+                        shell_quote(signing_key),
+                        shell_quote(&repository.path),
+                        shell_quote(&package_file),
                     );
-                    let tmp_command = format!(
-                        "repo-add -R {}/nomispaz.db.tar.zst {}/{}",
-                        local_repo_dir,
-                        local_repo_dir,
-                        file_name.to_string_lossy()
-                    );
-
-                    let commands: Vec<&str> = vec![&tmp_command];
+                    if signing_key.is_empty() {
+                        commands.push(format!("repo-add -R {} {}", database, package));
+                    } else {
+                        commands.push(format!(
+                            "gpg --batch --yes --detach-sign --no-armor --use-agent -u {} --output {} {}",
+                            key,
+                            shell_quote(&format!("{}.sig", package_file)),
+                            package
+                        ));
+                        commands.push(format!(
+                            "repo-add -R --sign --verify --key {} {} {}",
+                            key, database, package
+                        ));
+                    }
 
-                    let _ = run_commands_stdout(commands);
+                    // the package is only added if it was signed
+                    let command = commands.join(" && ");
+                    let output = run_commands_stdout(vec![&command])?;
+                    if !output.status.success() {
+                        return Err(format!(
+                            "{} couldn't be added: {}",
+                            file_name.to_string_lossy(),
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        )
+                        .into());
+                    }
                 }
             }
             Err(err) => eprintln!("Error reading entry: {:?}", err),
@@ -308,6 +834,32 @@ fn update_repository(
     Ok(())
 }
 
+fn local_repo_siglevel(configs: &Config) -> &str {
+    //! returns the SigLevel of the local repositories in pacman.conf
+    //! signed repositories require valid signatures for packages and databases
+    if configs.signing_key.is_empty() {
+        "Optional TrustAll"
+    } else {
+        "Required"
+    }
+}
+
+fn check_signing_key(signing_key: &str) -> Result<(), String> {
+    //! returns an error if the secret key for signing isn't available in the keyring of the user
+    if signing_key.is_empty() {
+        return Ok(());
+    }
+    match Command::new("gpg")
+        .args(["--batch", "--list-secret-keys", signing_key])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(format!("Secret key {} for signing not found", signing_key)),
+    }
+}
+
 fn cleanup(config: &Config) {
     //! cleans the build directory
 
@@ -320,22 +872,11 @@ fn cleanup(config: &Config) {
 
 fn get_installed_version(packagename: &str) -> Result<String, String> {
     //! takes the package name and returns version-revision of the installed package
-    // Construct the command
-    let tmp_command = format!(
-        "pacman -Q | grep \"\\<{}\\>\" | cut -d' ' -f 2",
-        packagename
-    );
-
-    let commands: Vec<&str> = vec![&tmp_command];
-
-    match run_commands_stdout(commands) {
-        Ok(result) => {
-            if result.stdout.len() > 0 {
-                Ok(String::from_utf8_lossy(&result.stdout).into_owned())
-            } else {
-                Err(format!("No version found for package {}", packagename))
-            }
-        }
+    //! if no package with the name is installed, the first installed package of a split package
+    //! with the name as pkgbase is used
+    match localdb::find_package_or_base(localdb::LOCAL_DB_PATH, packagename) {
+        Ok(Some(package)) => Ok(package.version),
+        Ok(None) => Err(format!("No version found for package {}", packagename)),
         Err(e) => Err(format!(
             "Error while reading package version of {}: {}",
             packagename, e
@@ -392,7 +933,7 @@ fn modify_file(
             break;
         }
         let re = Regex::new(&format!("^{}$", replacement))?;
-        replacement_already_exist = re.is_match(&line);
+        replacement_already_exist = re.is_match(line);
     }
 
     // try to replace the content
@@ -409,7 +950,7 @@ fn modify_file(
     // only perform, if the replacement wasn't already in the file as a complete line
     if !replacement_already_exist {
         let tmp_file_split: Vec<&str> = filename.rsplitn(2, "/").collect();
-        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.get(0).unwrap());
+        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.first().unwrap());
         let _ = File::create(&tmp_file);
         let _ = write(&tmp_file, modified_content);
 
@@ -423,6 +964,7 @@ fn modify_file(
     Ok(())
 }
 
+#[allow(dead_code)]
 fn evaluate_extra_configs(
     filename: &str,
     extra_config: &str,
@@ -464,7 +1006,7 @@ fn evaluate_extra_configs(
 
     for line in content_lines {
         // check if there are stll entrys in the extra configs to be added to the file
-        match config_lines.get(0) {
+        match config_lines.first() {
             // no config lines need to be added any more
             None => {
                 // add the original line
@@ -475,7 +1017,7 @@ fn evaluate_extra_configs(
                 // the current line of the file equals the current config line
                 match config_block {
                     false => {
-                        if config_lines.get(0).unwrap().trim() == line.trim() {
+                        if config_lines.first().unwrap().trim() == line.trim() {
                             // we are in the config block
                             config_block = true;
                             // remove the current config line since it was printed
@@ -484,7 +1026,7 @@ fn evaluate_extra_configs(
                         modified_content.push_str(&format!("{}\n", line));
                     }
                     true => {
-                        if config_lines.get(0).unwrap().trim() == line.trim() {
+                        if config_lines.first().unwrap().trim() == line.trim() {
                             modified_content.push_str(&format!("{}\n", line));
                             // remove the current config line since it was printed
                             config_lines.remove(0);
@@ -518,7 +1060,7 @@ fn evaluate_extra_configs(
     // only write file if it was changed
     if content != modified_content {
         let tmp_file_split: Vec<&str> = filename.rsplitn(2, "/").collect();
-        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.get(0).unwrap());
+        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.first().unwrap());
         let _ = File::create(&tmp_file);
         let _ = write(&tmp_file, modified_content);
 
@@ -532,18 +1074,9 @@ fn evaluate_extra_configs(
     Ok(())
 }
 
-fn evaluate_config_changes(
-    filename: &str,
-    extra_config: &str,
-    build_dir: &str,
-    sudo: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    //! writes the desired config in a temporary file and afterwards runs nvim -d to diff the desired
-    //! config with the existing config file
-    //! only runs nvim -d if the desired config does not already exist in the config file
-
-    // first check if the config alread exists in the destination
-    // initialize file contents
+fn read_system_file(filename: &str, sudo: bool) -> Result<String, Box<dyn std::error::Error>> {
+    //! takes the filename and returns the content of the file
+    //! if sudo is true, the file is read with sudo cat and trailing empty lines are removed
     let mut content: String = "".to_string();
 
     // if the file needs to be opened with sudo, it needs to be read with cat in linux
@@ -566,6 +1099,18 @@ fn evaluate_config_changes(
         }
     }
 
+    Ok(content)
+}
+
+fn config_change_required(
+    filename: &str,
+    extra_config: &str,
+    sudo: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    //! checks if the desired config already exists as a block in the config file
+    //! returns true if not all config lines were found and the file needs to be changed
+    let content = read_system_file(filename, sudo)?;
+
     // create a vector of all lines of the new configs and of the file contents
     let content_lines: Vec<String> = content.split("\n").map(|s| s.to_string()).collect();
     let mut config_lines: Vec<String> = extra_config.split("\n").map(|s| s.to_string()).collect();
@@ -578,7 +1123,7 @@ fn evaluate_config_changes(
 
     for line in content_lines {
         // check if there are stll entrys in the extra configs to be added to the file
-        match config_lines.get(0) {
+        match config_lines.first() {
             // all config lines were found in the block
             None => {
                 config_block_done = true;
@@ -587,12 +1132,12 @@ fn evaluate_config_changes(
             _ => {
                 match config_block {
                     false => {
-                        if config_lines.get(0).unwrap().trim() == line.trim() {
+                        if config_lines.first().unwrap().trim() == line.trim() {
                             // we are in the config block
                             config_block = true;
                             // remove the current config line since it was successfully checked
                             config_lines.remove(0);
-                            match config_lines.get(0) {
+                            match config_lines.first() {
                                 None => {
                                     config_block_done = true;
                                     break;
@@ -603,11 +1148,11 @@ fn evaluate_config_changes(
                     }
                     true => {
                         // we are in the config block
-                        if config_lines.get(0).unwrap().trim() == line.trim() {
+                        if config_lines.first().unwrap().trim() == line.trim() {
                             // remove the current config line since it was found in the file
                             config_lines.remove(0);
 
-                            match config_lines.get(0) {
+                            match config_lines.first() {
                                 None => {
                                     config_block_done = true;
                                     break;
@@ -625,13 +1170,27 @@ fn evaluate_config_changes(
         }
     }
 
-    if !config_block_done {
+    Ok(!config_block_done)
+}
+
+fn evaluate_config_changes(
+    filename: &str,
+    extra_config: &str,
+    build_dir: &str,
+    sudo: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    //! writes the desired config in a temporary file and afterwards runs nvim -d to diff the desired
+    //! config with the existing config file
+    //! only runs nvim -d if the desired config does not already exist in the config file
+
+    // first check if the config alread exists in the destination
+    if config_change_required(filename, extra_config, sudo)? {
         // not all config lines were found --> run nvim -d to diff the desired config with the
         // current config.
 
         // save the config change to a new file in the temporary build-directory
         let tmp_file_split: Vec<&str> = filename.rsplitn(2, "/").collect();
-        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.get(0).unwrap());
+        let tmp_file = format!("{}/{}", build_dir, tmp_file_split.first().unwrap());
         let _ = File::create(&tmp_file);
         let _ = write(&tmp_file, extra_config);
 
@@ -652,12 +1211,16 @@ fn evaluate_config_changes(
     Ok(())
 }
 
-fn load_config_from_file(file_path: &str, args: &Args) -> Config {
+fn load_config_from_file(file_path: &str, args: &Args, command: &Commands) -> Config {
     //! takes the path to the config file, parses the toml files and returns a config struct
     let content = read_to_string(file_path).expect("Failed to read TOML configfile");
     let mut configs: Config =
         toml::from_str(&content).expect("Errors in the TOML-structure of the configfile");
 
+    if args.demote {
+        configs.demote = true;
+    }
+
     // use pacconfig from args if available
     if args.pacconfig != "none" {
         configs.pacconfig = args.pacconfig.clone();
@@ -674,36 +1237,102 @@ fn load_config_from_file(file_path: &str, args: &Args) -> Config {
     // if overlay-dir starts with ~ or $HOME, parse the directory
     configs.mirrorlist = resolve_home(configs.mirrorlist);
 
-    let mut local_repo_dir: String = String::new();
+    // the local repository from local_repo receives all packages that aren't added to one of the
+    // additional repositories
+    let mut repositories: Vec<LocalRepo> = vec![LocalRepo {
+        path: configs.local_repo.clone(),
+        name: configs.local_repo_name.clone(),
+        packages: String::new(),
+    }];
+    repositories.append(&mut configs.local_repos);
+
+    configs.local_repo = "none".to_string();
+    for (index, repository) in repositories.into_iter().enumerate() {
+        if let Some(repository) =
+            resolve_local_repo(repository, &configs.signing_key, args, command)
+        {
+            if index == 0 {
+                configs.local_repo = repository.dir();
+            }
+            configs.local_repos.push(repository);
+        }
+    }
+
+    if configs.local_repo == "none" {
+        // builds need the main repository
+        configs.local_repos.clear();
+    }
 
-    if configs.local_repo.ends_with(".db.tar.zst") {
-        configs.local_repo = resolve_home(configs.local_repo);
+    configs
+}
 
-        let check_file_exists = Path::new(&configs.local_repo);
-        if check_file_exists.is_file() {
-            local_repo_dir = configs.local_repo.rsplit_once("/").unwrap().0.to_string();
-        } else {
-            // initiate, if anything other then no or n is defined
-            if args.initiate != "no" && args.initiate != "n" {
-                println!("Repository Db.tar.zst-file doesn't exist. It will be created");
-                initiate_repo(&configs);
-            } else {
-                local_repo_dir = "none".to_string();
-                println!("{}", "Repository Db.tar.zst-file doesn't exist --> no local builds are possible. To create the file restart with -i yes".red());
-            }
+fn resolve_local_repo(
+    mut repository: LocalRepo,
+    signing_key: &str,
+    args: &Args,
+    command: &Commands,
+) -> Option<LocalRepo> {
+    //! takes a configured local repository and returns it with the path to its db-file and its
+    //! name
+    //! the path is either the db-file or the directory that contains the db-file of the named
+    //! repository
+    //! returns None if the db-file doesn't exist and can't be created
+    repository.path = resolve_home(repository.path.trim_end_matches('/').to_string());
+
+    if !["", "patched", "overlays"].contains(&repository.packages.as_str()) {
+        println!(
+            "{}",
+            format!(
+                "Unknown packages {} of the local repository {} --> use \"patched\" or \"overlays\".",
+                repository.packages, repository.path
+            )
+            .red()
+        );
+        return None;
+    }
+
+    if repository.path.ends_with(".db.tar.zst") {
+        let file_name = syncdb::repository_name(&repository.path);
+        if repository.name.is_empty() {
+            repository.name = file_name;
+        } else if repository.name != file_name {
+            // pacman looks for the database file with the name of the repository
+            println!(
+                "{}",
+                format!(
+                    "Name {} of the local repository doesn't match the db-file {} --> no local builds are possible.",
+                    repository.name, repository.path
+                )
+                .red()
+            );
+            return None;
         }
+    } else if !repository.name.is_empty() && repository.path != "none" {
+        repository.path = format!("{}/{}.db.tar.zst", repository.path, repository.name);
     } else {
-        local_repo_dir = "none".to_string();
         println!(
             "{}",
             "No db.tar.zst-file for local repository specified --> no local builds are possible."
                 .red()
         );
+        return None;
     }
 
-    configs.local_repo = local_repo_dir;
+    if !Path::new(&repository.path).is_file() {
+        // only initiate if the init command was given
+        if *command == Commands::Init && !args.dry_run {
+            println!(
+                "Repository Db.tar.zst-file {} doesn't exist. It will be created",
+                repository.path
+            );
+            initiate_repo(&repository, signing_key);
+        } else {
+            println!("{}", format!("Repository Db.tar.zst-file {} doesn't exist --> no local builds are possible. To create the file restart with the init command", repository.path).red());
+            return None;
+        }
+    }
 
-    configs
+    Some(repository)
 }
 
 fn resolve_home(old_path: String) -> String {
@@ -723,21 +1352,64 @@ fn resolve_home(old_path: String) -> String {
     new_path
 }
 
-fn initiate_repo(config: &Config) {
+fn shell_quote(value: &str) -> String {
+    //! quotes a value so that bash passes it as one word without expanding it
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn initiate_repo(repository: &LocalRepo, signing_key: &str) {
+    //! initiate nompac.
+    //! Takes a local repository and the signing key
+    //! Creates the directory and the db-file of the local repository, the db-file is signed if a
+    //! signing key is defined
+    let _ = std::fs::create_dir_all(repository.dir());
+    if signing_key.is_empty() {
+        create_cmd_thread(vec![format!("repo-add {}", repository.path)], true);
+    } else {
+        create_cmd_thread(
+            vec![format!(
+                "repo-add --sign --key {} {}",
+                signing_key, repository.path
+            )],
+            true,
+        );
+    }
+}
+
+fn initiate_signing_key(config: &Config) {
     //! initiate nompac.
     //! Takes config struct
-    //! Creates local repo according to the defined local_repo config option
-    let local_repo_file = config.local_repo.split("/").last().unwrap().to_string();
-    create_cmd_thread(vec![format!("repo-add {}", local_repo_file)], true);
+    //! Adds the signing key to the keyring of pacman and signs it locally, so that pacman trusts
+    //! the signed packages of the local repositories
+    if config.signing_key.is_empty() {
+        return;
+    }
+    let key_file = format!("{}/signing_key.asc", config.build_dir);
+    let _ = std::fs::create_dir_all(&config.build_dir);
+    create_cmd_thread(
+        vec![
+            format!(
+                "gpg --batch --yes --armor --output {} --export {}",
+                shell_quote(&key_file),
+                shell_quote(&config.signing_key)
+            ),
+            format!("sudo pacman-key --add {}", shell_quote(&key_file)),
+            format!(
+                "sudo pacman-key --lsign-key {}",
+                shell_quote(&config.signing_key)
+            ),
+        ],
+        true,
+    );
 }
 
 fn initiate_pacmanconf(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     //! initiate nompac.
     //! Takes config struct
-    //! Updates pacman.conf with configured mirrorlist and adds local repo
+    //! Updates pacman.conf with configured mirrorlist and adds the local repositories
 
     // change mirrorlist to the one configured
-    let _ = modify_file(
+    modify_file(
         &config.pacconfig,
         "Include.*mirrorlist",
         &format!("Include = {}", &config.mirrorlist),
@@ -746,31 +1418,57 @@ fn initiate_pacmanconf(config: &Config) -> Result<(), Box<dyn std::error::Error>
         false,
     )?;
 
-    // add local repository
-    if config.local_repo != "none" {
-        let contents = read_to_string(&config.pacconfig)?;
-        let mut modified_content: String = String::new();
-        let mut already_inserted = false;
-        for line in contents.lines() {
-            // insert local repo before the first defined repository found
-            if !already_inserted
-                & (line.ends_with("[core-testing]")
-                    | line.ends_with("[core]")
-                    | line.ends_with("[extra-testing]")
-                    | line.ends_with("[extra]")
-                    | line.ends_with("[multilib]"))
-            {
-                modified_content.push_str("[nomispaz]\n");
-                modified_content.push_str("SigLevel = Optional TrustAll\n");
-                modified_content.push_str(&format!("Server = file://{}\n\n", config.local_repo));
-                modified_content.push_str(&format!("{}\n", line));
-                already_inserted = true;
-            } else {
-                modified_content.push_str(&format!("{}\n", line));
+    // add local repositories that aren't defined yet
+    let contents = read_to_string(&config.pacconfig)?;
+    let siglevel = local_repo_siglevel(config);
+    let local_sections: Vec<String> = config
+        .local_repos
+        .iter()
+        .map(|repository| format!("[{}]", repository.name))
+        .collect();
+    let repositories: Vec<&LocalRepo> = config
+        .local_repos
+        .iter()
+        .filter(|repository| {
+            !contents
+                .lines()
+                .any(|line| line.trim() == format!("[{}]", repository.name))
+        })
+        .collect();
+
+    let mut modified_content: String = String::new();
+    let mut already_inserted = false;
+    let mut in_local_section = false;
+    for line in contents.lines() {
+        if line.trim().starts_with('[') {
+            in_local_section = local_sections.iter().any(|section| line.trim() == section);
+        }
+
+        // insert local repo before the first defined repository found
+        if !already_inserted
+            & (line.ends_with("[core-testing]")
+                | line.ends_with("[core]")
+                | line.ends_with("[extra-testing]")
+                | line.ends_with("[extra]")
+                | line.ends_with("[multilib]"))
+        {
+            for repository in &repositories {
+                modified_content.push_str(&format!("[{}]\n", repository.name));
+                modified_content.push_str(&format!("SigLevel = {}\n", siglevel));
+                modified_content.push_str(&format!("Server = file://{}\n\n", repository.dir()));
             }
+            modified_content.push_str(&format!("{}\n", line));
+            already_inserted = true;
+        } else if in_local_section && line.trim().starts_with("SigLevel") {
+            // existing local repositories get the SigLevel of the signing settings
+            modified_content.push_str(&format!("SigLevel = {}\n", siglevel));
+        } else {
+            modified_content.push_str(&format!("{}\n", line));
         }
+    }
 
-        // Write new content to file
+    // Write new content to file
+    if modified_content != contents {
         write(&config.pacconfig, &modified_content)?;
     }
     Ok(())
@@ -836,55 +1534,110 @@ fn create_cmd_thread(command: Vec<String>, print: bool) {
 
     // make sure, that the thread is finished before continuing
     match command_result.join() {
-        Ok(_) => println!(""),
+        Ok(_) => println!(),
         Err(e) => eprintln!("Main thread: Failed to join thread: {:?}", e),
     }
 }
 
-fn collect_package_lists(configs: &Config) -> (Vec<String>, Vec<String>) {
-    //! returns lists for the packages to be removed or installed
+fn resolve_package_groups(
+    configs: &Config,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    //! returns the members of the declared package groups without the excluded packages and the
+    //! group that pulled each member in
+    let mut members: HashMap<String, String> = HashMap::new();
+    if configs.package_groups.is_empty() {
+        return Ok(members);
+    }
 
-    // get list of explicitely installed packages
-    let mut package_list_installed: Vec<String> = String::from_utf8_lossy(
-        &Command::new("bash")
-            .arg("-c")
-            .arg("pacman -Qe | cut -d' ' -f 1")
-            .output()
-            .map_err(|e| eprintln!("List of installed packages couldn't be collected: {e}"))
-            .unwrap()
-            .stdout,
-    )
-    .split("\n")
-    .map(|s| s.to_string())
-    .collect();
+    if configs.sync_dbs.is_empty() {
+        return Err("The sync databases couldn't be read".into());
+    }
+    for group in &configs.package_groups {
+        let mut found = false;
+        for package in configs
+            .sync_dbs
+            .iter()
+            .flat_map(|database| &database.packages)
+        {
+            if !package.groups.iter().any(|name| name == group.name()) {
+                continue;
+            }
+            found = true;
+            if !group.exclude().contains(&package.name) {
+                members
+                    .entry(package.name.clone())
+                    .or_insert_with(|| group.name().to_string());
+            }
+        }
+        if !found {
+            println!(
+                "{}",
+                format!(
+                    "Package group {} has no members in the sync databases.",
+                    group.name()
+                )
+                .yellow()
+            );
+        }
+    }
 
-    // remove last item from list since the split function returns one additional empty row
-    package_list_installed.pop();
+    Ok(members)
+}
 
-    // add all packages to the list that were installed as part of a group
-    let mut package_list_grouped_installed: Vec<String> = String::from_utf8_lossy(
-        &Command::new("bash")
-            .arg("-c")
-            .arg("pacman -Sg gnome | cut -d ' ' -f 2")
-            .output()
-            .map_err(|e| eprintln!("List of installed packages via groups couldn't be collected: {e}"))
-            .unwrap()
-            .stdout,
-    )
-    .split("\n")
-    .map(|s| s.to_string())
-    .collect();
-    package_list_grouped_installed.pop();
+fn collect_package_lists(
+    configs: &Config,
+) -> (
+    Vec<String>,
+    Vec<String>,
+    Vec<String>,
+    HashMap<String, String>,
+) {
+    //! returns lists for the packages to be removed or installed, the declared packages that are
+    //! installed as dependencies and the package group that pulled in a package
+    //! the declared packages that are installed as dependencies are only listed separately if
+    //! undeclared packages are demoted, otherwise they are installed again
 
-    let mut package_list_installed_cleared: Vec<String> = Vec::new();
-    for package in &package_list_installed {
-        if !package_list_grouped_installed.contains(package) {
-            package_list_installed_cleared.push(package.to_string()); 
+    let installed = localdb::read_local_db(localdb::LOCAL_DB_PATH)
+        .map_err(|e| eprintln!("List of installed packages couldn't be collected: {e}"))
+        .unwrap_or_default();
+
+    // get list of explicitely installed packages
+    let package_list_installed: Vec<String> = installed
+        .iter()
+        .filter(|package| package.reason == localdb::InstallReason::Explicit)
+        .map(|package| package.name.clone())
+        .collect();
+    let package_list_dependencies: Vec<String> = installed
+        .iter()
+        .filter(|package| package.reason == localdb::InstallReason::Dependency)
+        .map(|package| package.name.clone())
+        .collect();
+
+    // members of the declared package groups are wanted as well
+    // if the groups can't be resolved, their members can't be told apart from packages that
+    // should be removed --> nothing is removed
+    let (group_members, groups_resolved) = match resolve_package_groups(configs) {
+        Ok(members) => (members, true),
+        Err(e) => {
+            println!(
+                "{}",
+                format!(
+                    "Members of the package groups couldn't be resolved: {} --> no packages are removed.",
+                    e
+                )
+                .red()
+            );
+            (HashMap::new(), false)
         }
-    }
+    };
 
     // create package list of packages that should be installed explicitely
     let mut package_list = configs.packages.clone();
+    for member in group_members.keys() {
+        if !package_list.contains(member) {
+            package_list.push(member.to_string());
+        }
+    }
     // sort list by package name
     package_list.sort();
 
@@ -892,22 +1645,376 @@ fn collect_package_lists(configs: &Config) -> (Vec<String>, Vec<String>) {
     // for this, iterate over list and remove the package already read from the vector
     let mut packages_to_remove: Vec<String> = Vec::new();
 
-    for package in &package_list_installed_cleared {
-        if !package_list.contains(&package) {
-            packages_to_remove.push(package.to_string());
+    if groups_resolved {
+        for package in &package_list_installed {
+            if !package_list.contains(package) {
+                packages_to_remove.push(package.to_string());
+            }
         }
     }
 
     // search for packages that are in the config file but not explicitely installed
     let mut packages_to_install: Vec<String> = Vec::new();
+    let mut packages_to_mark_explicit: Vec<String> = Vec::new();
 
     for package in package_list {
-        if !package_list_installed_cleared.contains(&package) {
+        if package_list_installed.contains(&package) {
+            continue;
+        }
+        if configs.demote && package_list_dependencies.contains(&package) {
+            packages_to_mark_explicit.push(package);
+        } else {
             packages_to_install.push(package);
         }
     }
 
-    return (packages_to_remove, packages_to_install);
+    // only the packages that aren't declared directly show their group
+    let mut package_groups = group_members;
+    package_groups.retain(|package, _| !configs.packages.contains(package));
+
+    (
+        packages_to_remove,
+        packages_to_install,
+        packages_to_mark_explicit,
+        package_groups,
+    )
+}
+
+fn protected_packages(configs: &Config) -> Vec<String> {
+    //! returns the built-in protected packages and the ones of the config
+    let mut protected: Vec<String> = DEFAULT_PROTECTED.iter().map(|s| s.to_string()).collect();
+    protected.extend(configs.protected.iter().cloned());
+    protected
+}
+
+fn packages_to_demote(configs: &Config, packages_to_remove: &[String]) -> Vec<String> {
+    //! returns the undeclared packages that can be marked as dependencies
+    //! protected packages and the packages they require stay explicitly installed since the
+    //! orphan cleanup would remove them
+    let installed = match localdb::read_local_db(localdb::LOCAL_DB_PATH) {
+        Ok(installed) => installed,
+        // without the dependencies, only the protected packages themselves are kept
+        Err(e) => {
+            eprintln!("List of installed packages couldn't be collected: {e}");
+            Vec::new()
+        }
+    };
+    let (kept, demoted) =
+        split_demotion(&installed, &protected_packages(configs), packages_to_remove);
+    if !kept.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "Protected packages stay explicitly installed: {}",
+                kept.join(" ")
+            )
+            .yellow()
+        );
+    }
+    demoted
+}
+
+fn split_demotion(
+    installed: &[localdb::InstalledPackage],
+    protected: &[String],
+    packages_to_remove: &[String],
+) -> (Vec<String>, Vec<String>) {
+    //! takes the installed packages, the protected packages and the undeclared packages and
+    //! returns the packages that stay explicitly installed and the ones that are demoted
+    let required = localdb::dependency_closure(installed, protected);
+    packages_to_remove
+        .iter()
+        .cloned()
+        .partition(|package| protected.contains(package) || required.contains_key(package))
+}
+
+fn protected_removals(configs: &Config, packages_to_remove: &[String]) -> Vec<String> {
+    //! returns the packages of the removal that are protected or required by a protected package
+    //! with the reason
+    //! the removal includes the dependencies that pacman -Rs removes together with the packages
+    let protected = protected_packages(configs);
+
+    let installed = match localdb::read_local_db(localdb::LOCAL_DB_PATH) {
+        Ok(installed) => installed,
+        // without the dependencies, only the protected packages themselves can be checked
+        Err(e) => {
+            eprintln!("List of installed packages couldn't be collected: {e}");
+            Vec::new()
+        }
+    };
+    check_removal(&installed, &protected, packages_to_remove)
+}
+
+fn check_removal(
+    installed: &[localdb::InstalledPackage],
+    protected: &[String],
+    packages_to_remove: &[String],
+) -> Vec<String> {
+    //! takes the installed packages, the protected packages and the packages to remove and
+    //! returns the packages of the removal (including the dependencies that are removed with
+    //! them) that are protected or required by a protected package with the reason
+    let required = localdb::dependency_closure(installed, protected);
+
+    let mut removal: Vec<(String, String)> =
+        localdb::removal_closure(installed, packages_to_remove)
+            .into_iter()
+            .collect();
+    // without the local database only the packages themselves are known
+    for package in packages_to_remove {
+        if !removal.iter().any(|(name, _)| name == package) {
+            removal.push((package.clone(), package.clone()));
+        }
+    }
+    removal.sort();
+
+    removal
+        .iter()
+        .filter_map(|(package, target)| {
+            let reason = if protected.contains(package) {
+                "protected".to_string()
+            } else {
+                format!("required by {}", required.get(package)?)
+            };
+            if package == target {
+                Some(format!("{} ({})", package, reason))
+            } else {
+                Some(format!(
+                    "{} ({}, removed as dependency of {})",
+                    package, reason, target
+                ))
+            }
+        })
+        .collect()
+}
+
+fn confirm_removal(packages_to_remove: &[String]) -> bool {
+    //! asks the user to confirm the removal of many packages
+    println!(
+        "{}",
+        format!(
+            "\n{} packages would be removed:\n {}\nContinue with the removal? (y/N)",
+            packages_to_remove.len(),
+            packages_to_remove.join(" ")
+        )
+        .red()
+    );
+
+    let mut confirmation = String::new();
+    stdin()
+        .read_line(&mut confirmation)
+        .expect("Failed to read line");
+
+    confirmation.to_lowercase().trim() == "y"
+}
+
+fn package_with_group(package: &str, package_groups: &HashMap<String, String>) -> String {
+    //! returns the package name with the group that pulled the package in
+    match package_groups.get(package) {
+        Some(group) => format!("{} (group {})", package, group),
+        None => package.to_string(),
+    }
+}
+
+fn check_declared_names(configs: &Config) -> Vec<String> {
+    //! returns a message for every declared package, group and overlay that doesn't exist and
+    //! prints the packages that are installed through a provider
+    //! the check is skipped if the sync databases can't be read
+    if configs.sync_dbs.is_empty() {
+        println!(
+            "{}",
+            "Declared names couldn't be checked without the sync databases.".yellow()
+        );
+        return Vec::new();
+    }
+    let (unknown, notices) = validate::validate(configs, &configs.sync_dbs);
+    for notice in notices {
+        println!("{}", notice.yellow());
+    }
+    unknown.iter().map(|name| name.message()).collect()
+}
+
+// struct to contain everything a run would change on the system
+#[derive(Debug, Default)]
+struct Plan {
+    packages_to_remove: Vec<String>,
+    packages_to_install: Vec<String>,
+    // package group that pulled in a package
+    package_groups: HashMap<String, String>,
+    // packages of the removal that are protected with the reason, the removal is refused
+    protected_removals: Vec<String>,
+    // the removal has to be confirmed
+    confirm_removal: bool,
+    // undeclared packages that are marked as dependencies instead of removed
+    packages_to_demote: Vec<String>,
+    // declared packages that are installed as dependencies
+    packages_to_mark_explicit: Vec<String>,
+    // declared packages, groups and overlays that don't exist, the transaction is refused
+    unknown_names: Vec<String>,
+    // package name and version that would be built
+    patched_packages: Vec<(String, String)>,
+    overlay_packages: Vec<(String, String)>,
+    // paths of files that would be created or changed
+    changed_files: Vec<String>,
+    system_update: bool,
+}
+
+fn create_plan(
+    configs: &Config,
+    path_to_config: &str,
+    args: &Args,
+    command: &Commands,
+    date: &[String],
+) -> Plan {
+    //! runs all checks of the phases included in the command without changing the system and
+    //! returns the result as plan
+    let mut plan = Plan::default();
+
+    if command.includes(&Commands::Build) || command.includes(&Commands::Update) {
+        plan.unknown_names = check_declared_names(configs);
+    }
+
+    // patched and overlay packages are only built if a local repository exists
+    if command.includes(&Commands::Build) && configs.local_repo != "none" {
+        for package in patched_package_names(configs) {
+            if let Some(version) = check_patched_package(configs, package, true) {
+                plan.patched_packages.push((package.to_string(), version));
+            }
+        }
+        for package in &configs.overlays {
+            if let Some(version) = check_overlay_package(configs, package) {
+                plan.overlay_packages.push((package.to_string(), version));
+            }
+        }
+    }
+
+    if command.includes(&Commands::Update) && date[0] != "none" {
+        plan.system_update = true;
+        plan.changed_files.push(format!(
+            "{}/mirrorlist",
+            path_to_config.rsplit_once("/").unwrap().0
+        ));
+        (
+            plan.packages_to_remove,
+            plan.packages_to_install,
+            plan.packages_to_mark_explicit,
+            plan.package_groups,
+        ) = collect_package_lists(configs);
+        if configs.demote {
+            plan.packages_to_demote = packages_to_demote(configs, &plan.packages_to_remove);
+            plan.packages_to_remove.clear();
+        }
+        plan.protected_removals = protected_removals(configs, &plan.packages_to_remove);
+        plan.confirm_removal = plan.packages_to_remove.len() > configs.max_removals;
+    }
+
+    if command.includes(&Commands::Configs) {
+        for entry in &configs.configs {
+            let file_path_resolved = resolve_home(entry.path.clone());
+
+            // a missing file will always be created
+            if !Path::new(&file_path_resolved).exists() {
+                plan.changed_files.push(file_path_resolved);
+                continue;
+            }
+
+            for config_entry in entry.clone() {
+                match config_change_required(&file_path_resolved, &config_entry.extra_config, true)
+                {
+                    Ok(true) => {
+                        plan.changed_files.push(file_path_resolved.clone());
+                        break;
+                    }
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Error while checking {}: {}", file_path_resolved, e),
+                }
+            }
+        }
+    }
+
+    // the snapshot date is written back to the config file
+    if command.includes(&Commands::Update)
+        && args.snapshot != "none"
+        && args.snapshot != configs.snapshot
+    {
+        plan.changed_files.push(path_to_config.to_string());
+    }
+
+    plan
+}
+
+fn print_plan(plan: &Plan) {
+    //! prints the plan in the order the steps would be executed
+    println!(
+        "{}",
+        "\nPlanned transaction (dry run, nothing will be executed):".blue()
+    );
+
+    if !plan.unknown_names.is_empty() {
+        println!(
+            "{}",
+            "\nThe transaction would be refused since the config declares unknown names:".red()
+        );
+        for name in &plan.unknown_names {
+            println!("  {}", name.red());
+        }
+    }
+
+    println!("{}", "\nPatched packages to build:".blue());
+    for (package, version) in &plan.patched_packages {
+        println!("  {} {}", package, version);
+    }
+
+    println!("{}", "\nOverlay packages to build:".blue());
+    for (package, version) in &plan.overlay_packages {
+        println!("  {} {}", package, version);
+    }
+
+    println!("{}", "\nPackages to remove:".red());
+    for package in &plan.packages_to_remove {
+        println!("  {}", package.red());
+    }
+    if !plan.protected_removals.is_empty() {
+        println!(
+            "{}",
+            "The removal and the update would be refused since the removal touches protected packages:"
+                .red()
+        );
+        for package in &plan.protected_removals {
+            println!("  {}", package.red());
+        }
+    } else if plan.confirm_removal {
+        println!(
+            "{}",
+            "The removal has to be confirmed since it exceeds max_removals, otherwise the update is refused."
+                .yellow()
+        );
+    }
+
+    println!("{}", "\nPackages to mark as dependencies:".yellow());
+    for package in &plan.packages_to_demote {
+        println!("  {}", package.yellow());
+    }
+
+    println!("{}", "\nPackages to mark as explicitly installed:".blue());
+    for package in &plan.packages_to_mark_explicit {
+        println!("  {}", package_with_group(package, &plan.package_groups));
+    }
+
+    println!("{}", "\nPackages to install:".blue());
+    for package in &plan.packages_to_install {
+        println!("  {}", package_with_group(package, &plan.package_groups));
+    }
+
+    if plan.system_update {
+        println!("{}", "\nA full system update will be performed.".blue());
+    } else {
+        println!("{}", "\nNo system update will be performed.".green());
+    }
+
+    println!("{}", "\nFiles to change:".blue());
+    for file in &plan.changed_files {
+        println!("  {}", file);
+    }
 }
 
 fn perform_config_changes(configs: &Config) {
@@ -924,8 +2031,8 @@ fn perform_config_changes(configs: &Config) {
                 let _ = File::create(&file_path_resolved);
             } else {
                 let (path, _) = file_path_resolved.rsplit_once("/").unwrap();
-                let _ = create_cmd_thread(vec![format!("sudo mkdir {path}")], true);
-                let _ = create_cmd_thread(vec![format!("sudo touch {}", &entry.path)], true);
+                create_cmd_thread(vec![format!("sudo mkdir {path}")], true);
+                create_cmd_thread(vec![format!("sudo touch {}", &entry.path)], true);
             }
             println!("File {file_path_resolved} created.");
         }
@@ -941,30 +2048,34 @@ fn perform_config_changes(configs: &Config) {
     }
 }
 
-fn collect_settings(
-    file_path: &str,
-) -> (
+// packages, overlays, config-changes, patches and package groups collected from an imported config
+// file
+type Settings = (
     Vec<String>,
     Vec<String>,
     Vec<SystemConfigs>,
-    Vec<HashMap<String, Vec<String>>>,
-) {
+    Vec<HashMap<String, PackagePatches>>,
+    Vec<PackageGroup>,
+);
+
+fn collect_settings(file_path: &str) -> Settings {
     //! collect the packages, overlays and config-changes defined in the given file and return them
 
     // first read the contents of the file into a toml value (at this point it is not known, what fields exist in the toml file)
 
     // Read the file contents into a string
-    let contents =
-        read_to_string(file_path).expect(&format!("Couldn't read config file: {}", file_path));
+    let contents = read_to_string(file_path)
+        .unwrap_or_else(|_| panic!("Couldn't read config file: {}", file_path));
 
     // Parse the string into a `serde::Value`
-    let toml_table: Table =
-        toml::from_str(&contents).expect(&format!("Couldn't parse config file {}", file_path));
+    let toml_table: Table = toml::from_str(&contents)
+        .unwrap_or_else(|_| panic!("Couldn't parse config file {}", file_path));
 
     let mut packages: Vec<String> = vec![];
     let mut overlays: Vec<String> = vec![];
     let mut config_changes: Vec<SystemConfigs> = vec![];
-    let mut patches: Vec<HashMap<String, Vec<String>>> = vec![];
+    let mut patches: Vec<HashMap<String, PackagePatches>> = vec![];
+    let mut package_groups: Vec<PackageGroup> = vec![];
 
     // collect the packages
     for entry in toml_table {
@@ -995,6 +2106,15 @@ fn collect_settings(
                 .map(|value| value.clone().try_into::<SystemConfigs>().unwrap())
                 .collect();
         }
+        if entry.0 == "package_groups" {
+            package_groups = entry
+                .1
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|value| value.clone().try_into::<PackageGroup>().unwrap())
+                .collect();
+        }
         if entry.0 == "patches" {
             patches = entry
                 .1
@@ -1004,37 +2124,79 @@ fn collect_settings(
                 .map(|value| {
                     value
                         .clone()
-                        .try_into::<HashMap<String, Vec<String>>>()
+                        .try_into::<HashMap<String, PackagePatches>>()
                         .unwrap()
                 })
                 .collect();
         }
     }
 
-    (packages, overlays, config_changes, patches)
+    (packages, overlays, config_changes, patches, package_groups)
+}
+
+fn remove_orphans(configs: &Config) {
+    //! removes the packages that were installed as dependencies and aren't required anymore
+    //! the orphans and the dependencies removed with them are checked against the protected
+    //! packages like any other removal, e.g. the ones demoted by --demote
+    println!("Search and remove orphaned packages");
+    let orphans: Vec<String> = match Command::new("pacman").arg("-Qdtq").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        Err(e) => {
+            eprintln!("List of orphaned packages couldn't be collected: {e}");
+            return;
+        }
+    };
+    if orphans.is_empty() {
+        return;
+    }
+
+    let blocked_removals = protected_removals(configs, &orphans);
+    if !blocked_removals.is_empty() {
+        println!(
+            "{}",
+            "Refusing to remove orphaned packages since the removal touches protected packages (add them to the config or to protected):"
+                .red()
+        );
+        for package in &blocked_removals {
+            println!("  {}", package.red());
+        }
+    } else if orphans.len() > configs.max_removals && !confirm_removal(&orphans) {
+        println!("{}", "Removal of the orphaned packages skipped.".yellow());
+    } else {
+        println!("{}", orphans.join(" ").red());
+        create_cmd_thread(
+            vec![format!("sudo pacman -Rns {}", orphans.join(" "))],
+            true,
+        );
+    }
 }
 
-fn cleanup_system() {
-    let mut command: Vec<String> = vec![];
+fn cleanup_system(configs: &Config) {
     // show failed daemons
-    command.push("systemctl --failed".to_string());
-    command.push("echo \"Search and remove orphaned packages\"".to_string());
-    command.push("sudo pacman -Qdtq | sudo pacman -Rns -".to_string());
-    command.push("echo \"cleanup the package cache (keep the last version)\"".to_string());
-    command.push("sudo paccache -rk1".to_string());
-    command.push("echo \"remove all uninstaled packages from the package cache\"".to_string());
-    command.push("sudo paccache -ruk0".to_string());
+    create_cmd_thread(vec!["systemctl --failed".to_string()], true);
+    remove_orphans(configs);
+    let command: Vec<String> = vec![
+        "echo \"cleanup the package cache (keep the last version)\"".to_string(),
+        "sudo paccache -rk1".to_string(),
+        "echo \"remove all uninstaled packages from the package cache\"".to_string(),
+        "sudo paccache -ruk0".to_string(),
+    ];
     create_cmd_thread(command, true);
 }
 
 fn rebuild_grub() {
-    let mut command: Vec<String> = vec![];
-    command.push("sudo grub-install --target=x86_64-efi --efi-directory=/boot/efi".to_string());
-    command.push("sudo grub-mkconfig -o /boot/grub/grub.cfg".to_string());
+    let command: Vec<String> = vec![
+        "sudo grub-install --target=x86_64-efi --efi-directory=/boot/efi".to_string(),
+        "sudo grub-mkconfig -o /boot/grub/grub.cfg".to_string(),
+    ];
     create_cmd_thread(command, true);
 }
 
-fn set_user_groups(configs: &Config) {
+fn set_user_groups(_configs: &Config) {
     // get current user groups
     let mut current_user_groups: Vec<String> = String::from_utf8_lossy(
         &Command::new("bash")
@@ -1051,366 +2213,607 @@ fn set_user_groups(configs: &Config) {
     // remove the last entry in the vector since it is always empty
     current_user_groups.pop();
     let mut current_user_groups_sorted: Vec<&str> =
-        current_user_groups.get(0).unwrap().split(" ").collect();
+        current_user_groups.first().unwrap().split(" ").collect();
     current_user_groups_sorted.sort();
 
     println!("{:?}", current_user_groups_sorted);
 }
 
-fn main() {
-    // define and read command line arguments
-    let args = Args::parse();
-
-    let mut path_to_config = args.config.clone();
-
-    path_to_config = resolve_home(path_to_config);
-
-    // Import basic settings from the config-file
-    let mut configs = load_config_from_file(&path_to_config, &args);
-
-    // collect settings from imported config-files defined in the original config file
-    for file_name in configs.imports.clone() {
-        let additional_settings: (
-            Vec<String>,
-            Vec<String>,
-            Vec<SystemConfigs>,
-            Vec<HashMap<String, Vec<String>>>,
-        ) = collect_settings(&resolve_home(file_name));
-        configs.packages.extend(additional_settings.0);
-        configs.overlays.extend(additional_settings.1);
-        configs.configs.extend(additional_settings.2);
-        configs.patches.extend(additional_settings.3);
-    }
-
-    // initiate pacman.conf if required
-    if args.initiate != "no" && args.initiate != "n" {
-        let _ = initiate_pacmanconf(&configs);
+fn setup_chroot(configs: &Config, date: &[String]) -> Result<Option<Chroot>, String> {
+    //! returns the chroot for the builds if build_mode is chroot
+    //! the chroot is pinned to the snapshot date, without a snapshot the servers of the configured
+    //! mirrorlist are used
+    match configs.build_mode.as_str() {
+        "host" => return Ok(None),
+        "chroot" => {}
+        build_mode => return Err(format!("Unknown build_mode {}", build_mode)),
     }
 
-    // get snapshot date
-    let date: Vec<String>;
-
-    let original_snapshot = configs.snapshot.clone();
-
-    // if a snapshot was defined in the arguments, replace the one from the config file
-    if args.snapshot == "none" {
-        date = configs.snapshot.split('_').map(|s| s.to_string()).collect();
+    let chroot = Chroot {
+        dir: if configs.chroot_dir.is_empty() {
+            format!("{}/chroot", configs.build_dir)
+        } else {
+            resolve_home(configs.chroot_dir.clone())
+        },
+        local_repo_dirs: {
+            let mut dirs: Vec<String> = configs
+                .local_repos
+                .iter()
+                .map(|repository| repository.dir())
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            dirs
+        },
+    };
+
+    let servers: Vec<String> = if date[0] != "none" {
+        vec![snapshot_server(date)]
     } else {
-        date = args.snapshot.split('_').map(|s| s.to_string()).collect();
+        read_to_string(&configs.mirrorlist)
+            .map_err(|e| format!("Mirrorlist {} couldn't be read: {}", configs.mirrorlist, e))?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("Server"))
+            .map(|server| {
+                server
+                    .trim_start()
+                    .trim_start_matches('=')
+                    .trim()
+                    .to_string()
+            })
+            .collect()
+    };
+    if servers.is_empty() {
+        return Err("No servers found for the chroot".to_string());
     }
 
-    // all settings are collected --> print the result for the user
-    println!("{}", "Used settings:".blue());
-    println!("Used config file: {}", path_to_config);
-    println!("Local build directory: {}", configs.build_dir);
-    println!("Local repository: {}", configs.local_repo);
-    println!("Patch directory: {}", configs.patch_dir);
-    println!("Overlay directory: {}", configs.overlay_dir);
-    println!("pacman.conf location: {}", configs.pacconfig);
-    println!("Snaphot date: {}_{}_{}", date[0], date[1], date[2]);
+    let local_repos: Vec<(String, String)> = configs
+        .local_repos
+        .iter()
+        .map(|repository| (repository.name.clone(), repository.dir()))
+        .collect();
 
-    //building custom packages and overlays
-    if configs.local_repo != "none".to_string() {
-        println!("{}", "\nBuilding patched upstream-packages".blue());
+    builder::prepare_chroot(
+        &chroot,
+        &builder::chroot_pacman_conf(&local_repos, local_repo_siglevel(configs), &servers),
+        &builder::log_file_path(&configs.build_dir, "chroot"),
+    )
+    .map_err(|e| e.to_string())?;
 
-        // create necessary directories
-        // build directory
-        let _ = std::fs::create_dir_all(format!("{}/src", configs.build_dir));
+    Ok(Some(chroot))
+}
 
-        // apply patches, build new package and update local repository
-        for package in configs.patches[0].keys() {
-            let mut package_version_repo: String = String::new();
-            let mut package_version_installed: String = String::new();
+fn build_packages(configs: &Config, date: &[String]) -> bool {
+    //! builds all patched upstream-packages and overlays that are outdated and adds them to the
+    //! local repository
+    //! prints a summary with the result of every package at the end
+    //! returns true if every package is built or up to date
+    let (Some(patched_repository), Some(overlay_repository)) = (
+        repository_for(configs, "patched"),
+        repository_for(configs, "overlays"),
+    ) else {
+        println!(
+            "{}",
+            "No local repository available --> skipping package builds.".red()
+        );
+        // without packages to build, there is no need for a local repository
+        return patched_package_names(configs).is_empty() && configs.overlays.is_empty();
+    };
 
-            match get_current_version_from_repo(&package) {
-                Ok(version) => package_version_repo = version,
-                Err(e) => println!(
-                    "{}",
-                    format!(
-                        "Package version in repository of package {} couldn't be determined: {}",
-                        package, e
-                    )
-                    .red()
-                ),
-            }
-            match get_installed_version(&package) {
-                Ok(version) => package_version_installed = version,
-                Err(e) => println!(
-                    "{}",
-                    format!(
-                        "Package version of installed package {} couldn't be determined: {}",
-                        package, e
-                    )
-                    .red()
-                ),
-            }
+    if let Err(e) = check_signing_key(&configs.signing_key) {
+        println!("{}", format!("{} --> skipping package builds.", e).red());
+        return false;
+    }
 
-            //only procede if the package was updated upstream
-            if package_version_installed.trim() != package_version_repo.trim()
-                && package_version_repo.trim() != "0-0"
-            {
-                let _ = get_current_tarball_from_repo(
-                    &package,
+    // all builds use the same chroot
+    let chroot = match setup_chroot(configs, date) {
+        Ok(chroot) => chroot,
+        Err(e) => {
+            println!("{}", format!("{} --> skipping package builds.", e).red());
+            return false;
+        }
+    };
+
+    println!("{}", "\nPreparing patched upstream-packages".blue());
+
+    let mut reports: Vec<BuildReport> = Vec::new();
+    // packages that are prepared in the build directory and are built after all packages are
+    // prepared, so that they can be built in the order of their dependencies
+    let mut jobs: Vec<BuildJob> = Vec::new();
+
+    // create necessary directories
+    // build directory
+    let _ = std::fs::create_dir_all(format!("{}/src", configs.build_dir));
+
+    // download the packages and apply the patches
+    for package in patched_package_names(configs) {
+        //only procede if the package was updated upstream
+        if let Some(package_version_repo) = check_patched_package(configs, package, false) {
+            // the build directory uses the version with the colon of the epoch replaced by a dash
+            let package_tag = package_version_repo.replace(':', "-");
+            let pkg_build_dir = format!("{}/src/{}-{}/", configs.build_dir, package, package_tag);
+            let Some(package_patches) = package_patches(configs, package) else {
+                continue;
+            };
+
+            if let Err(e) = source::backend(configs, &package_patches.source()).fetch(
+                package,
+                &package_version_repo,
+                &pkg_build_dir,
+            ) {
+                reports.push(BuildReport::new(
+                    package,
                     &package_version_repo,
-                    &format!(
-                        "{}/{}-{}.tar.gz",
-                        configs.build_dir, package, package_version_repo
-                    ),
-                );
+                    BuildResult::Skipped(format!("download failed: {}", e)),
+                ));
+                continue;
+            }
 
+            let Some(patch_entries) = apply_patches(
+                configs,
+                package_patches.patches(),
+                package,
+                &package_version_repo,
+                chroot.as_ref(),
+            ) else {
                 println!(
-                    "{}/{}-{}.tar.gz",
-                    &configs.build_dir, &package, &package_version_repo
-                );
-                let _ = extract_tgz(
-                    &format!(
-                        "{}/{}-{}.tar.gz",
-                        &configs.build_dir, &package, &package_version_repo
-                    ),
-                    &format!("{}/src/", &configs.build_dir),
+                    "{}",
+                    format!("Patches of {} don't apply --> skipping the build.", package).red()
                 );
-
-                apply_patches(
-                    &configs,
-                    &configs.patches[0][package],
-                    &package,
+                reports.push(BuildReport::new(
+                    package,
                     &package_version_repo,
-                );
-
-                build_package(&format!(
-                    "{}/src/{}-{}/",
-                    configs.build_dir, package, package_version_repo
+                    BuildResult::Skipped("patches don't apply".to_string()),
                 ));
+                continue;
+            };
+
+            jobs.push(BuildJob::new(
+                package,
+                &package_version_repo,
+                &pkg_build_dir,
+                patch_entries,
+                &patched_repository.name,
+            ));
+        } else {
+            println!(
+                "{}",
+                format!("Package {} already up to date.", package).green()
+            );
+            reports.push(BuildReport::new(package, "", BuildResult::UpToDate));
+        }
+    }
 
-                let _ = update_repository(&configs, &configs.local_repo, &package);
+    // prepare packages from overlays
+    println!("{}", "\nPreparing packages from overlay".blue());
 
-                cleanup(&configs);
-            } else {
-                println!(
-                    "{}",
-                    format!("Package {} already up to date.", package).green()
-                );
+    for package in &configs.overlays {
+        if let Some(package_version_overlay) = check_overlay_package(configs, package) {
+            // there is no package in the repository
+
+            // copy necessary files from overlay to build directory
+            for entry in WalkDir::new(format!("{}/{}", &configs.overlay_dir, package))
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+            {
+                if entry.path().is_file() {
+                    let _ =
+                        std::fs::create_dir_all(format!("{}/src/{}/", configs.build_dir, package));
+                    let _ = copy(
+                        entry.path(),
+                        format!(
+                            "{}/src/{}/{}",
+                            configs.build_dir,
+                            package,
+                            entry.file_name().to_str().unwrap()
+                        ),
+                    );
+                    println!("{}/src/{}/", configs.build_dir, package);
+                }
             }
+
+            jobs.push(BuildJob::new(
+                package,
+                &package_version_overlay,
+                &format!("{}/src/{}/", configs.build_dir, package),
+                Vec::new(),
+                &overlay_repository.name,
+            ));
+        } else {
+            println!(
+                "{}",
+                format!("Package {} already up to date.", package).green()
+            );
+            reports.push(BuildReport::new(package, "", BuildResult::UpToDate));
         }
+    }
 
-        // build packages from overlays
-        println!("{}", "\nBuilding packages from overlay".blue());
+    // build the packages after their dependencies
+    let graph = builder::dependency_graph(&jobs);
+    let (order, cycles) = builder::topological_order(&graph);
 
-        for package in &configs.overlays {
-            let mut package_version_overlay: String = String::new();
-            let mut package_version_installed: String = "0".to_string();
+    for cycle in &cycles {
+        let mut names: Vec<&str> = cycle
+            .iter()
+            .map(|&job| jobs[job].package.as_str())
+            .collect();
+        names.push(names[0]);
+        println!(
+            "{}",
+            format!("Error: dependency cycle {}", names.join(" -> ")).red()
+        );
+    }
 
-            match get_installed_version(&package) {
-                Ok(version) => package_version_installed = version,
-                Err(e) => println!(
-                    "{}",
-                    format!(
-                        "Package version of installed package {} couldn't be determined: {}",
-                        package, e
-                    )
-                    .red()
-                ),
-            }
+    reports.extend(run_builds(configs, &jobs, &graph, &order, chroot.as_ref()));
+
+    // jobs in a dependency cycle or depending on one
+    for (index, job) in jobs.iter().enumerate() {
+        if !order.contains(&index) {
+            let reason = match cycles.iter().find(|cycle| cycle.contains(&index)) {
+                Some(_) => "dependency cycle".to_string(),
+                None => "depends on a dependency cycle".to_string(),
+            };
+            reports.push(BuildReport::new(
+                &job.package,
+                &job.version,
+                BuildResult::Skipped(reason),
+            ));
+        }
+    }
 
-            match get_version_from_overlay(&configs.overlay_dir, &package) {
-                Ok(version) => package_version_overlay = version,
-                Err(e) => println!(
-                    "{}",
-                    format!(
-                        "Package version of package {} from overlay couldn't be determined: {}",
-                        package, e
-                    )
-                    .red()
-                ),
-            }
+    cleanup(configs);
+    // the sources downloaded by makepkg count towards the size limit of the cache
+    Cache::new(&configs.build_dir, configs.cache_size).enforce_limit("");
 
-            // check if the package exists in the official repos and is newer
-            let mut package_version_repo: String = String::new();
+    builder::print_summary(&reports);
 
-            match get_current_version_from_repo(&package) {
-                Ok(version) => package_version_repo = version,
-                Err(_e) => println!(
-                    "{}",
-                    format!(
-                        "Error while trying to fetch package version of {} from official repository. This doesn't happen since the package doesn't exist - this case should be handled.",
-                        package
-                    )
-                    .green()
-                ),
-            }
+    reports
+        .iter()
+        .all(|report| matches!(report.result, BuildResult::Built | BuildResult::UpToDate))
+}
 
-            // only continue if a package version exists in the repos (i.e. version is 0-0)
-            if package_version_overlay.trim() != package_version_repo.trim()
-                && package_version_repo.trim() != "0-0"
-            {
-                println!("{}", format!("Package {} has different version in official repo --> consider updating the version or remove the package from the overlay.", package).blue());
-            }
+fn run_builds(
+    configs: &Config,
+    jobs: &[BuildJob],
+    graph: &[Vec<usize>],
+    order: &[usize],
+    chroot: Option<&Chroot>,
+) -> Vec<BuildReport> {
+    //! builds the jobs in the given order with up to max_parallel_builds builds at the same time
+    //! a job is started as soon as all of its dependencies are built, jobs whose dependencies
+    //! failed are skipped
+    //! the local repository is only updated by this thread, so updates never run concurrently
+    let mut reports: Vec<BuildReport> = Vec::new();
+
+    // makepkg -s installs the dependencies on the host and pacman can only run once at a time
+    let max_parallel_builds = if chroot.is_some() {
+        configs.max_parallel_builds.max(1)
+    } else {
+        if configs.max_parallel_builds > 1 {
+            println!(
+                "{}",
+                "Parallel builds require build_mode = \"chroot\" --> building one package at a time."
+                    .yellow()
+            );
+        }
+        1
+    };
+
+    println!("{}", "\nBuilding packages".blue());
+
+    // makepkg downloads the sources once for all builds
+    let srcdest = Cache::new(&configs.build_dir, configs.cache_size).srcdest();
+
+    let mut schedule = builder::Schedule::new(graph, order);
+    let mut running: Vec<usize> = Vec::new();
+
+    let (tx, rx) = mpsc::channel::<(usize, String, Result<(), String>)>();
+
+    thread::scope(|scope| {
+        loop {
+            // start all jobs whose dependencies are done
+            while running.len() < max_parallel_builds {
+                let index = match schedule.next() {
+                    Some(builder::Step::Build(index)) => index,
+                    Some(builder::Step::Skip(index, dependency)) => {
+                        reports.push(BuildReport::new(
+                            &jobs[index].package,
+                            &jobs[index].version,
+                            BuildResult::Skipped(format!(
+                                "dependency {} wasn't built",
+                                jobs[dependency].package
+                            )),
+                        ));
+                        continue;
+                    }
+                    None => break,
+                };
+                let job = &jobs[index];
+
+                let log_file = builder::log_file_path(&configs.build_dir, &job.package);
+                if let Err(e) = builder::write_log_header(
+                    &log_file,
+                    &job.package,
+                    &job.version,
+                    &job.pkg_build_dir,
+                    &job.patches,
+                ) {
+                    println!(
+                        "{}",
+                        format!("Build log {} couldn't be created: {}", log_file, e).red()
+                    );
+                }
 
-            if package_version_installed.trim() != package_version_overlay.trim() {
-                // first check if the package was alread build and is available in the local repo
-                let package_version_from_repo: Vec<String> = String::from_utf8_lossy(
-                    &Command::new("bash")
-                        .arg("-c")
-                        .arg(format!(
-                            "pacman -Ss | grep \"\\<{}\\>\" | cut -d' ' -f 2",
-                            &package
-                        ))
-                        .output()
-                        .map_err(|e| eprintln!("Version of package couldn't be determined: {e}"))
-                        .unwrap()
-                        .stdout,
-                )
-                .split("\n")
-                .map(|s| s.to_string())
-                .collect();
+                // the output of parallel builds is prefixed with the package name
+                let output_prefix = if max_parallel_builds > 1 {
+                    format!("[{}] ", job.package)
+                } else {
+                    String::new()
+                };
+
+                println!("{}", format!("Starting build of {}", job.package).blue());
+                running.push(index);
+                let tx = tx.clone();
+                let srcdest = &srcdest;
+                scope.spawn(move || {
+                    let result =
+                        builder::build_package(job, &log_file, chroot, srcdest, &output_prefix);
+                    let _ = tx.send((index, log_file, result));
+                });
+            }
 
-                if package_version_from_repo.get(0).unwrap() == ""
-                    || package_version_from_repo.get(0).unwrap().trim()
-                        != package_version_overlay.trim()
-                {
-                    // there is no package in the repository
+            if running.is_empty() {
+                break;
+            }
 
-                    // copy necessary files from overlay to build directory
-                    for entry in WalkDir::new(&format!("{}/{}", &configs.overlay_dir, package))
-                        .into_iter()
-                        .filter_map(|entry| entry.ok())
+            // wait for the next build to finish
+            let Ok((index, log_file, result)) = rx.recv() else {
+                break;
+            };
+            running.retain(|job| *job != index);
+            let job = &jobs[index];
+
+            match result {
+                Ok(()) => {
+                    let update = match configs
+                        .local_repos
+                        .iter()
+                        .find(|repository| repository.name == job.repository)
                     {
-                        if entry.path().is_file() {
-                            let _ = std::fs::create_dir_all(format!(
-                                "{}/src/{}/",
-                                configs.build_dir, package
-                            ));
-                            let _ = copy(
-                                entry.path(),
-                                format!(
-                                    "{}/src/{}/{}",
-                                    configs.build_dir,
-                                    package,
-                                    entry.file_name().to_str().unwrap()
-                                ),
-                            );
-                            println!("{}/src/{}/", configs.build_dir, package);
+                        Some(repository) => {
+                            update_repository(repository, &job.pkg_build_dir, &configs.signing_key)
                         }
+                        None => Err(format!("unknown repository {}", job.repository).into()),
+                    };
+                    // a package that isn't in the repository can't be used by its dependents
+                    if let Err(e) = update {
+                        println!(
+                            "{}",
+                            format!(
+                                "Local repository couldn't be updated with {}: {}",
+                                job.package, e
+                            )
+                            .red()
+                        );
+                        schedule.fail(index);
+                        reports.push(BuildReport::new(
+                            &job.package,
+                            &job.version,
+                            BuildResult::Skipped(format!(
+                                "built, but not added to [{}]: {}",
+                                job.repository, e
+                            )),
+                        ));
+                        continue;
+                    }
+                    schedule.finish(index);
+                    reports.push(BuildReport::new(
+                        &job.package,
+                        &job.version,
+                        BuildResult::Built,
+                    ));
+
+                    // the dependencies are installed with pacman --> the database of the local
+                    // repository has to be synced before a dependent package is built
+                    if schedule.has_pending_dependents(index) {
+                        let sync_command = match chroot {
+                            Some(chroot) => chroot.nspawn("pacman -Sy --noconfirm"),
+                            None => format!("sudo pacman -Sy --config {}", configs.pacconfig),
+                        };
+                        create_cmd_thread(vec![sync_command], true);
                     }
-
-                    // build the package
-                    build_package(&format!("{}/src/{}/", configs.build_dir, package));
-
-                    let _ = update_repository(&configs, &configs.local_repo, &package);
-
-                    //create_cmd_thread(
-                    //    vec![format!(
-                    //        "sudo pacman -Sy {} --config {}",
-                    //        package, &configs.pacconfig
-                    //    )],
-                    //    true,
-                    //);
-
-                    cleanup(&configs);
                 }
-            } else {
-                println!(
-                    "{}",
-                    format!("Package {} already up to date.", package).green()
-                );
+                Err(e) => {
+                    println!(
+                        "{}",
+                        format!("Build of {} failed: {}", job.package, e).red()
+                    );
+                    schedule.fail(index);
+                    reports.push(BuildReport::new(
+                        &job.package,
+                        &job.version,
+                        BuildResult::Failed(log_file),
+                    ));
+                }
             }
         }
-    }
+    });
 
-    // perform system update
-    if date[0] != "none" {
-        // update snapshot that will be used for the update
-        let _ = modify_file(
-            &format!("{}/mirrorlist", path_to_config.rsplit_once("/").unwrap().0),
-            ".*archive.archlinux.org.*",
-            &format!(
-                "Server = https://archive.archlinux.org/repos/{}/{}/{}/$$repo/os/$$arch",
-                date[0], date[1], date[2]
-            ),
-            &configs.build_dir,
-            true,
-            false,
+    reports
+}
+
+// result of the update phase
+#[derive(Debug, PartialEq)]
+enum UpdateResult {
+    Updated,
+    // no snapshot date is defined, nothing was changed
+    Skipped,
+    // the removal of the undeclared packages was refused or not confirmed
+    Refused,
+}
+
+fn update_system(configs: &Config, path_to_config: &str, date: &[String]) -> UpdateResult {
+    //! pins the mirrorlist to the snapshot date, removes and installs packages according to the
+    //! config and performs a full system update
+    //! returns if the update was performed, skipped or refused
+    //! a refused removal refuses the whole transaction before the mirrorlist is pinned, so the
+    //! system stays at its snapshot
+    if date[0] == "none" {
+        println!(
+            "{}",
+            "No snapshot date defined --> no update will be performed.".red()
         );
+        return UpdateResult::Skipped;
+    }
 
-        let (packages_to_remove, packages_to_install) = collect_package_lists(&configs);
+    let (packages_to_remove, packages_to_install, packages_to_mark_explicit, package_groups) =
+        collect_package_lists(configs);
 
-        // only perform if packages have to be removed
-        if packages_to_remove.len() > 0 {
+    // a removal that touches the base system is refused completely
+    if !configs.demote {
+        let blocked_removals = protected_removals(configs, &packages_to_remove);
+        if !blocked_removals.is_empty() {
             println!(
                 "{}",
-                "Removing the following packages since they don't exist in the config file:".red()
+                "Refusing to remove packages since the removal touches protected packages (add them to the config or to protected) --> no update will be performed:"
+                    .red()
             );
-            let mut command: Vec<String> = Vec::new();
-            let mut package_list: String = String::new();
-            for package in packages_to_remove {
-                package_list.push_str(" ");
-                package_list.push_str(&package);
+            for package in &blocked_removals {
+                println!("  {}", package.red());
             }
-            command.push(format!("sudo pacman -Rsn {}", package_list));
-            println!("{}", format!("{}", package_list).red());
-
-            create_cmd_thread(command, true);
+            return UpdateResult::Refused;
+        }
+        if packages_to_remove.len() > configs.max_removals && !confirm_removal(&packages_to_remove)
+        {
+            println!(
+                "{}",
+                "Removal of the packages not confirmed --> no update will be performed.".yellow()
+            );
+            return UpdateResult::Refused;
         }
+    }
 
-        // only perform if packages have to be installed
-        if packages_to_install.len() > 0 {
+    // update snapshot that will be used for the update
+    let _ = modify_file(
+        &format!("{}/mirrorlist", path_to_config.rsplit_once("/").unwrap().0),
+        ".*archive.archlinux.org.*",
+        // $ has to be escaped in the replacement of the regex
+        &format!("Server = {}", snapshot_server(date).replace('$', "$$")),
+        &configs.build_dir,
+        true,
+        false,
+    );
+
+    if configs.demote {
+        // the install reasons are changed instead, the orphan cleanup removes the rest
+        // protected packages and the packages they require aren't demoted
+        let packages_to_demote = packages_to_demote(configs, &packages_to_remove);
+        if !packages_to_demote.is_empty() {
+            println!(
+                "{}",
+                "Marking the following packages as dependencies since they don't exist in the config file:"
+                    .yellow()
+            );
+            println!("{}", packages_to_demote.join(" ").yellow());
+            create_cmd_thread(
+                vec![format!(
+                    "sudo pacman -D --asdeps {}",
+                    packages_to_demote.join(" ")
+                )],
+                true,
+            );
+        }
+        if !packages_to_mark_explicit.is_empty() {
             println!(
                 "{}",
-                "Installing the following packages and starting update:".blue()
+                "Marking the following packages as explicitly installed:".blue()
             );
-            let mut command: Vec<String> = Vec::new();
-            let mut package_list: String = String::new();
-            for package in packages_to_install {
-                package_list.push_str(" ");
-                package_list.push_str(&package);
+            for package in &packages_to_mark_explicit {
+                println!("{}", package_with_group(package, &package_groups).blue());
             }
+            create_cmd_thread(
+                vec![format!(
+                    "sudo pacman -D --asexplicit {}",
+                    packages_to_mark_explicit.join(" ")
+                )],
+                true,
+            );
+        }
+    } else if !packages_to_remove.is_empty() {
+        // only perform if packages have to be removed
+        println!(
+            "{}",
+            "Removing the following packages since they don't exist in the config file:".red()
+        );
+        let mut command: Vec<String> = Vec::new();
+        let mut package_list: String = String::new();
+        for package in packages_to_remove {
+            package_list.push(' ');
+            package_list.push_str(&package);
+        }
+        command.push(format!("sudo pacman -Rsn {}", package_list));
+        println!("{}", package_list.to_string().red());
 
-            command.push(format!(
-                "sudo pacman -Syu {} --config {}",
-                package_list, configs.pacconfig
-            ));
-            println!("{}", format!("{}", package_list).blue());
-            create_cmd_thread(command, true);
+        create_cmd_thread(command, true);
+    }
 
-            // after running the update, check for changed config files
-            let _ = Command::new("bash")
-                .arg("-c")
-                .arg("sudo DIFFPROG='nvim -d' pacdiff")
-                .status();
-        } else {
-            println!("{}", "Starting system update.\n".blue());
-            let mut command: Vec<String> = Vec::new();
+    // only perform if packages have to be installed
+    if !packages_to_install.is_empty() {
+        println!(
+            "{}",
+            "Installing the following packages and starting update:".blue()
+        );
+        let mut command: Vec<String> = Vec::new();
+        let mut package_list: String = String::new();
+        for package in &packages_to_install {
+            package_list.push(' ');
+            package_list.push_str(package);
+            println!("{}", package_with_group(package, &package_groups).blue());
+        }
 
-            command.push(format!("sudo pacman -Syu --config {}", configs.pacconfig));
-            create_cmd_thread(command, true);
+        command.push(format!(
+            "sudo pacman -Syu {} --config {}",
+            package_list, configs.pacconfig
+        ));
+        create_cmd_thread(command, true);
 
-            // after running the update, check for changed config files
-            let _ = Command::new("bash")
-                .arg("-c")
-                .arg("sudo DIFFPROG='nvim -d' pacdiff")
-                .status();
-        }
-    }
+        // after running the update, check for changed config files
+        let _ = Command::new("bash")
+            .arg("-c")
+            .arg("sudo DIFFPROG='nvim -d' pacdiff")
+            .status();
+    } else {
+        println!("{}", "Starting system update.\n".blue());
+        let mut command: Vec<String> = Vec::new();
 
-    if !configs.configs.is_empty() {
-        perform_config_changes(&configs);
+        command.push(format!("sudo pacman -Syu --config {}", configs.pacconfig));
+        create_cmd_thread(command, true);
+
+        // after running the update, check for changed config files
+        let _ = Command::new("bash")
+            .arg("-c")
+            .arg("sudo DIFFPROG='nvim -d' pacdiff")
+            .status();
     }
 
-    // remove old and orphaned packages, check for failed daemons
-    cleanup_system();
+    UpdateResult::Updated
+}
 
-    // update config file with the date that was given as snapshot
+fn save_snapshot(args: &Args, configs: &Config, path_to_config: &str) {
+    //! update config file with the date that was given as snapshot
     if args.snapshot != "none" {
         let _ = modify_file(
-            &resolve_home(args.config),
-            &format!("snapshot = \"{}\"", original_snapshot),
+            path_to_config,
+            &format!("snapshot = \"{}\"", configs.snapshot),
             &format!("snapshot = \"{}\"", args.snapshot),
             &configs.build_dir,
             true,
             false,
         );
     }
+}
 
+fn ask_rebuild_grub() {
     // rebuild grub in case there was a breaking change
     println!(
         "\n\nReinstall grub and generate grub.cfg? Should be done if grub update had breaking changes (y/N)"
@@ -1428,9 +2831,443 @@ fn main() {
             rebuild_grub();
         }
         _ => {
-            println!("");
+            println!();
         }
     }
+}
 
-    set_user_groups(&configs);
+fn print_status(plan: &Plan, configs: &Config) {
+    //! prints a short summary of the difference between the config and the system
+    println!("{}", "\nStatus:".blue());
+    println!("Declared packages: {}", configs.packages.len());
+    println!("Declared package groups: {}", configs.package_groups.len());
+    println!("Unknown declared names: {}", plan.unknown_names.len());
+    for name in &plan.unknown_names {
+        println!("  {}", name.red());
+    }
+    println!("Packages to remove: {}", plan.packages_to_remove.len());
+    println!("Packages to install: {}", plan.packages_to_install.len());
+    println!("Packages to demote: {}", plan.packages_to_demote.len());
+    println!(
+        "Packages to mark as explicit: {}",
+        plan.packages_to_mark_explicit.len()
+    );
+    println!("Outdated patched packages: {}", plan.patched_packages.len());
+    println!("Outdated overlay packages: {}", plan.overlay_packages.len());
+    println!("Config files out of sync: {}", plan.changed_files.len());
+}
+
+fn update_lockfile(configs: &Config, args: &Args, lockfile_path: &str, snapshot: &str) {
+    //! writes the resolved state to the lockfile
+    //! in locked mode, the lockfile is not changed but compared with the resolved state instead
+    let resolved = lock::resolve_state(configs, snapshot);
+
+    if !args.locked {
+        match lock::write_lockfile(lockfile_path, &resolved) {
+            Ok(_) => println!("Lockfile {} written.", lockfile_path),
+            Err(e) => eprintln!(
+                "{}",
+                format!("Lockfile {} couldn't be written: {}", lockfile_path, e).red()
+            ),
+        }
+        return;
+    }
+
+    match lock::read_lockfile(lockfile_path) {
+        Ok(lockfile) => {
+            let differences = lock::compare_state(&lockfile, &resolved);
+            if !differences.is_empty() {
+                println!(
+                    "{}",
+                    "System state after the run differs from the lockfile:".red()
+                );
+                for difference in differences {
+                    println!("  {}", difference.red());
+                }
+                std::process::exit(1);
+            }
+        }
+        Err(e) => eprintln!(
+            "{}",
+            format!("Lockfile {} couldn't be read: {}", lockfile_path, e).red()
+        ),
+    }
+}
+
+fn manage_repository(configs: &Config, action: &RepoCommands) -> Result<(), String> {
+    //! runs the repo command on the local repositories
+    if configs.local_repos.is_empty() {
+        return Err("No local repository available".to_string());
+    }
+    match action {
+        RepoCommands::List => repo::list(configs),
+        RepoCommands::Remove { package } => repo::remove(configs, package),
+        RepoCommands::Prune { keep, undeclared } => repo::prune(configs, *keep, *undeclared),
+        RepoCommands::Verify => match repo::verify(configs)? {
+            0 => Ok(()),
+            problems => Err(format!("{} problems found", problems)),
+        },
+    }
+}
+
+fn main() {
+    // define and read command line arguments
+    let args = Args::parse();
+
+    // without a subcommand, all phases are run
+    let command = args.command.clone().unwrap_or(Commands::Sync);
+
+    let mut path_to_config = args.config.clone();
+
+    path_to_config = resolve_home(path_to_config);
+
+    // Import basic settings from the config-file
+    let mut configs = load_config_from_file(&path_to_config, &args, &command);
+    configs.record_origins(&path_to_config);
+
+    // collect settings from imported config-files defined in the original config file
+    // imports with conditions are only used if the host and the profile match
+    let host = Host::detect();
+    let imports: Vec<Import> = configs
+        .imports
+        .iter()
+        .filter(|import| import.applies(&host, &args.profile))
+        .cloned()
+        .collect();
+    for import in &imports {
+        let file_path = resolve_home(import.path().to_string());
+        let additional_settings: Settings = collect_settings(&file_path);
+        configs.packages.extend(additional_settings.0);
+        configs.overlays.extend(additional_settings.1);
+        configs.configs.extend(additional_settings.2);
+        configs.patches.extend(additional_settings.3);
+        configs.package_groups.extend(additional_settings.4);
+        configs.record_origins(&file_path);
+    }
+
+    // if a snapshot was defined in the arguments, replace the one from the config file
+    let date: Vec<String> = if args.snapshot == "none" {
+        configs.snapshot.split('_').map(|s| s.to_string()).collect()
+    } else {
+        args.snapshot.split('_').map(|s| s.to_string()).collect()
+    };
+
+    // all settings are collected --> print the result for the user
+    println!("{}", "Used settings:".blue());
+    println!("Used config file: {}", path_to_config);
+    println!(
+        "Host: {} (cpu {}, {} pci devices)",
+        host.hostname,
+        host.cpu_vendor,
+        host.pci_devices.len()
+    );
+    println!("Profile: {}", args.profile);
+    println!("Imports: {} of {}", imports.len(), configs.imports.len());
+    for import in &imports {
+        let conditions = import.conditions();
+        if conditions.is_empty() {
+            println!("  {}", resolve_home(import.path().to_string()));
+        } else {
+            println!(
+                "  {} ({})",
+                resolve_home(import.path().to_string()),
+                conditions.join(", ")
+            );
+        }
+    }
+    println!("Local build directory: {}", configs.build_dir);
+    println!("Local repository: {}", configs.local_repo);
+    for repository in &configs.local_repos {
+        println!(
+            "  [{}] {}{}",
+            repository.name,
+            repository.path,
+            if repository.packages.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", repository.packages)
+            }
+        );
+    }
+    println!("Patch directory: {}", configs.patch_dir);
+    println!("Overlay directory: {}", configs.overlay_dir);
+    println!("pacman.conf location: {}", configs.pacconfig);
+    println!("Build mode: {}", configs.build_mode);
+    println!("Snaphot date: {}", date.join("_"));
+
+    // showing logs doesn't change the system
+    if let Commands::Logs { package, diff } = &command {
+        if let Err(e) = builder::show_logs(&configs.build_dir, package, *diff) {
+            println!("{}", e.to_string().red());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // the local repositories are managed independently of the other phases
+    if let Commands::Repo { action } = &command {
+        if let Err(e) = manage_repository(&configs, action) {
+            println!("{}", e.red());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // the versions of the patched packages, the package groups and the declared names are
+    // resolved with the databases of the snapshot, also in the build phase that runs before the
+    // system is pinned to the snapshot
+    if command.includes(&Commands::Build)
+        || command.includes(&Commands::Update)
+        || command == Commands::Status
+    {
+        match read_sync_databases(&configs, &date) {
+            Ok(databases) => configs.sync_dbs = databases,
+            Err(e) => println!("{}", e.yellow()),
+        }
+    }
+
+    let lockfile_path = lock::lockfile_path(&path_to_config);
+
+    // compare the state that would be resolved with the lockfile
+    if args.locked {
+        let lockfile = match lock::read_lockfile(&lockfile_path) {
+            Ok(lockfile) => lockfile,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Lockfile {} couldn't be read: {}", lockfile_path, e).red()
+                );
+                std::process::exit(1);
+            }
+        };
+        let differences = lock::check_before_run(
+            &lockfile,
+            &configs,
+            &date.join("_"),
+            command.includes(&Commands::Build),
+        );
+        if !differences.is_empty() {
+            println!(
+                "{}",
+                "Resolved state differs from the lockfile --> aborting:".red()
+            );
+            for difference in differences {
+                println!("  {}", difference.red());
+            }
+            std::process::exit(1);
+        }
+    }
+
+    // only show what would be done
+    if args.dry_run {
+        let plan = create_plan(&configs, &path_to_config, &args, &command, &date);
+        print_plan(&plan);
+        return;
+    }
+
+    // a misspelled or renamed package would let the whole transaction fail
+    // the names are checked against the databases of the snapshot before the mirrorlist is pinned
+    // or the system databases are refreshed, so a refusal leaves the system untouched
+    if command.includes(&Commands::Build) || command.includes(&Commands::Update) {
+        let unknown_names = check_declared_names(&configs);
+        if !unknown_names.is_empty() {
+            println!(
+                "{}",
+                "The config declares unknown names --> aborting:".red()
+            );
+            for name in unknown_names {
+                println!("  {}", name.red());
+            }
+            std::process::exit(1);
+        }
+    }
+
+    // the lockfile is only written if the phases succeeded, otherwise it would record a state
+    // that the system doesn't have
+    let mut succeeded = true;
+    match command {
+        Commands::Sync => {
+            succeeded &= build_packages(&configs, &date);
+            let update = update_system(&configs, &path_to_config, &date);
+            succeeded &= update != UpdateResult::Refused;
+            if !configs.configs.is_empty() {
+                perform_config_changes(&configs);
+            }
+            // remove old and orphaned packages, check for failed daemons
+            cleanup_system(&configs);
+            // the snapshot is only recorded if the system reached it
+            if update == UpdateResult::Updated {
+                save_snapshot(&args, &configs, &path_to_config);
+                ask_rebuild_grub();
+            }
+            set_user_groups(&configs);
+        }
+        Commands::Build => succeeded = build_packages(&configs, &date),
+        Commands::Configs => perform_config_changes(&configs),
+        Commands::Update => {
+            let update = update_system(&configs, &path_to_config, &date);
+            succeeded = update != UpdateResult::Refused;
+            // the snapshot is only recorded if the system reached it
+            if update == UpdateResult::Updated {
+                save_snapshot(&args, &configs, &path_to_config);
+                ask_rebuild_grub();
+            }
+        }
+        Commands::Clean => cleanup_system(&configs),
+        Commands::Init => {
+            initiate_signing_key(&configs);
+            let _ = initiate_pacmanconf(&configs);
+        }
+        Commands::Status => {
+            let plan = create_plan(&configs, &path_to_config, &args, &Commands::Sync, &date);
+            print_status(&plan, &configs);
+        }
+        // the logs and the local repositories are handled before the lockfile is checked
+        Commands::Logs { .. } | Commands::Repo { .. } => {}
+    }
+
+    // record the resolved state after all phases that change the system
+    if !succeeded {
+        println!(
+            "{}",
+            "Not all phases succeeded --> the lockfile is left unchanged.".yellow()
+        );
+    } else if matches!(
+        command,
+        Commands::Sync | Commands::Build | Commands::Update | Commands::Configs
+    ) {
+        update_lockfile(&configs, &args, &lockfile_path, &date.join("_"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL_DB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/local");
+
+    #[test]
+    fn import_conditions() {
+        let imports: HashMap<String, Vec<Import>> = toml::from_str(
+            r#"
+            imports = [
+              "common.toml",
+              { path = "desktop.toml", hostname = "desktop" },
+              { path = "amd.toml", cpu_vendor = "AMD" },
+              { path = "nvidia.toml", pci_device = "10de" },
+              { path = "rtx.toml", pci_device = "10de:2484" },
+              { path = "radeon.toml", pci_device = "1002" },
+              { path = "work.toml", profile = "work" },
+              { path = "desktop-work.toml", hostname = "desktop", profile = "work" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let imports = &imports["imports"];
+        assert!(matches!(&imports[0], Import::Path(path) if path == "common.toml"));
+        assert_eq!(
+            imports[7].conditions(),
+            vec!["hostname = desktop", "profile = work"]
+        );
+
+        let host = Host {
+            hostname: "desktop".to_string(),
+            cpu_vendor: "amd".to_string(),
+            pci_devices: vec!["10de:2484".to_string()],
+        };
+        let applied = |host: &Host, profile: &str| -> Vec<&str> {
+            imports
+                .iter()
+                .filter(|import| import.applies(host, profile))
+                .map(Import::path)
+                .collect()
+        };
+        assert_eq!(
+            applied(&host, ""),
+            vec![
+                "common.toml",
+                "desktop.toml",
+                "amd.toml",
+                "nvidia.toml",
+                "rtx.toml"
+            ]
+        );
+        assert_eq!(
+            applied(&host, "work"),
+            vec![
+                "common.toml",
+                "desktop.toml",
+                "amd.toml",
+                "nvidia.toml",
+                "rtx.toml",
+                "work.toml",
+                "desktop-work.toml"
+            ]
+        );
+
+        let laptop = Host {
+            hostname: "laptop".to_string(),
+            cpu_vendor: "intel".to_string(),
+            pci_devices: vec!["10de:1c8d".to_string(), "1002:73ff".to_string()],
+        };
+        assert_eq!(
+            applied(&laptop, "work"),
+            vec!["common.toml", "nvidia.toml", "radeon.toml", "work.toml"]
+        );
+        assert_eq!(applied(&Host::default(), ""), vec!["common.toml"]);
+    }
+
+    #[test]
+    fn check_removal_includes_cascaded_dependencies() {
+        let installed = localdb::read_local_db(LOCAL_DB).unwrap();
+        let protected = vec!["grub".to_string()];
+
+        // grub is only a dependency of desktop-meta and would be removed by pacman -Rs
+        assert_eq!(
+            check_removal(&installed, &protected, &["desktop-meta".to_string()]),
+            vec!["grub (protected, removed as dependency of desktop-meta)"]
+        );
+        assert_eq!(
+            check_removal(&installed, &protected, &["grub".to_string()]),
+            vec!["grub (protected)"]
+        );
+        assert!(check_removal(&installed, &protected, &["python-foo".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn check_removal_of_required_packages() {
+        let installed = localdb::read_local_db(LOCAL_DB).unwrap();
+        // python-foo requires python (via python3) which requires expat
+        let protected = vec!["python-foo".to_string()];
+
+        assert_eq!(
+            check_removal(&installed, &protected, &["python".to_string()]),
+            vec!["python (required by python-foo)"]
+        );
+        // packages that aren't installed are only checked by their name
+        assert_eq!(
+            check_removal(
+                &installed,
+                &protected,
+                &["python-foo".to_string(), "x".to_string()]
+            ),
+            vec!["python-foo (protected)"]
+        );
+    }
+
+    #[test]
+    fn split_demotion_keeps_protected_packages() {
+        let installed = localdb::read_local_db(LOCAL_DB).unwrap();
+        let protected = vec!["grub".to_string(), "python-foo".to_string()];
+        let undeclared = vec![
+            "grub".to_string(),
+            "python".to_string(),
+            "desktop-meta".to_string(),
+        ];
+
+        // python is required by python-foo and would be removed as orphan after the demotion
+        let (kept, demoted) = split_demotion(&installed, &protected, &undeclared);
+        assert_eq!(kept, vec!["grub", "python"]);
+        assert_eq!(demoted, vec!["desktop-meta"]);
+    }
 }
@@ -0,0 +1,1035 @@
+use colored::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fs::{canonicalize, create_dir_all, read_to_string, remove_dir_all, remove_file, write};
+use std::process::Command;
+
+use crate::builder::Chroot;
+use crate::pkgbuild::{Block, array_end, parse_assignment, scan_blocks, split_words};
+use crate::shell_quote;
+use crate::source::SourceConfig;
+use crate::vercmp::vercmp;
+
+// checksum arrays that have to contain one entry per source
+const CHECKSUM_ARRAYS: [&str; 8] = [
+    "cksums",
+    "md5sums",
+    "sha1sums",
+    "sha224sums",
+    "sha256sums",
+    "sha384sums",
+    "sha512sums",
+    "b2sums",
+];
+
+// struct to contain a patch that is added to a PKGBUILD
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PatchEntry {
+    pub file: String,
+    // number of leading path components that are removed (patch -p)
+    #[serde(default = "default_strip")]
+    pub strip: u32,
+    // directory relative to $srcdir in which the patch is applied
+    #[serde(default)]
+    pub directory: String,
+    // comma separated version constraints, e.g. ">=1.2, <2.0"
+    #[serde(default)]
+    pub condition: String,
+    // patches are applied in ascending order, entries with the same order keep the order of the
+    // config
+    #[serde(default)]
+    pub order: i64,
+}
+
+// patch in the config, either only the file name or a table with the settings of the patch
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PatchConfig {
+    File(String),
+    Table(PatchEntry),
+}
+
+fn default_strip() -> u32 {
+    1
+}
+
+impl PatchEntry {
+    pub fn new(file: &str) -> Self {
+        PatchEntry {
+            file: file.to_string(),
+            strip: default_strip(),
+            directory: String::new(),
+            condition: String::new(),
+            order: 0,
+        }
+    }
+
+    fn arguments(&self) -> String {
+        //! returns the arguments of the patch command
+        if self.directory.is_empty() {
+            format!("-Np{} -i \"${{srcdir}}/{}\"", self.strip, self.file)
+        } else {
+            format!(
+                "-Np{} -d \"${{srcdir}}/{}\" -i \"${{srcdir}}/{}\"",
+                self.strip, self.directory, self.file
+            )
+        }
+    }
+
+    fn command(&self) -> String {
+        //! returns the command that applies the patch in prepare()
+        format!("patch {}", self.arguments())
+    }
+
+    fn preflight_command(&self, index: usize, log_dir: &str) -> String {
+        //! returns the command that checks the patch with a dry run and writes the output and the
+        //! exit code of the dry run to the log directory
+        //! if the dry run succeeds, the patch is applied so that the following patches are checked
+        //! against the patched sources
+        format!(
+            "if patch --dry-run {arguments} > \"{log_dir}/{index}.log\" 2>&1; then patch {arguments} > /dev/null; echo 0 > \"{log_dir}/{index}.status\"; else echo $? > \"{log_dir}/{index}.status\"; fi",
+            arguments = self.arguments(),
+        )
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        //! takes the version of the package and returns true if the condition of the patch is
+        //! fulfilled
+        //! constraints without an epoch are compared with the version without the epoch, so that
+        //! pkgver ranges can be written without knowing the epoch
+        self.condition
+            .split(',')
+            .map(str::trim)
+            .filter(|constraint| !constraint.is_empty())
+            .all(|constraint| {
+                let operator_length = constraint
+                    .chars()
+                    .take_while(|c| matches!(c, '<' | '>' | '=' | '!'))
+                    .count();
+                let (operator, required) = constraint.split_at(operator_length);
+                let required = required.trim();
+                let version = match version.split_once(':') {
+                    Some((_, version)) if !required.contains(':') => version,
+                    _ => version,
+                };
+                let ordering = vercmp(version, required);
+                match operator {
+                    "<" => ordering == Ordering::Less,
+                    "<=" => ordering != Ordering::Greater,
+                    ">" => ordering == Ordering::Greater,
+                    ">=" => ordering != Ordering::Less,
+                    "!=" => ordering != Ordering::Equal,
+                    "" | "=" | "==" => ordering == Ordering::Equal,
+                    _ => {
+                        println!("Unknown operator {} in patch condition", operator);
+                        false
+                    }
+                }
+            })
+    }
+}
+
+impl PatchConfig {
+    pub fn entry(&self) -> PatchEntry {
+        //! returns the settings of the patch, plain file names use the defaults
+        match self {
+            PatchConfig::File(file) => PatchEntry::new(file),
+            PatchConfig::Table(entry) => entry.clone(),
+        }
+    }
+}
+
+// patches of a package in the config, either the list of patches or a table with the source of
+// the package and the patches
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PackagePatches {
+    List(Vec<PatchConfig>),
+    Table {
+        #[serde(default)]
+        source: SourceConfig,
+        #[serde(default)]
+        patches: Vec<PatchConfig>,
+    },
+}
+
+impl PackagePatches {
+    pub fn patches(&self) -> &[PatchConfig] {
+        match self {
+            PackagePatches::List(patches) => patches,
+            PackagePatches::Table { patches, .. } => patches,
+        }
+    }
+
+    pub fn source(&self) -> SourceConfig {
+        //! returns the source of the package, a list of patches uses the Arch GitLab
+        match self {
+            PackagePatches::List(_) => SourceConfig::Gitlab,
+            PackagePatches::Table { source, .. } => source.clone(),
+        }
+    }
+}
+
+pub fn select_patches(patches: &[PatchConfig], version: &str) -> Vec<PatchEntry> {
+    //! takes the patches of a package from the config and the version that is built and returns
+    //! the patches whose condition matches sorted by the apply order
+    let mut selected: Vec<PatchEntry> = patches
+        .iter()
+        .map(PatchConfig::entry)
+        .filter(|patch| patch.matches(version))
+        .collect();
+    selected.sort_by_key(|patch| patch.order);
+    selected
+}
+
+// result of the dry run of a patch
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchStatus {
+    Applies,
+    AppliesWithFuzz,
+    // number of rejected hunks
+    Fails(u32),
+    // the changes of the patch are already in the sources
+    Reversed,
+    // the patch is already part of the sources of the PKGBUILD and applied by it
+    InSources,
+    // the sources couldn't be prepared, so the patch wasn't checked
+    NotChecked,
+}
+
+impl PatchStatus {
+    pub fn applies(&self) -> bool {
+        //! returns true if the package can be built with the patch
+        matches!(
+            self,
+            PatchStatus::Applies | PatchStatus::AppliesWithFuzz | PatchStatus::InSources
+        )
+    }
+}
+
+// struct to contain the result of the dry run and the output of patch
+#[derive(Debug, Clone)]
+pub struct PatchReport {
+    pub file: String,
+    pub status: PatchStatus,
+    pub output: String,
+}
+
+fn last_assignment<'a>(blocks: &'a [Block], variable: &str) -> Option<&'a Block> {
+    //! returns the last top level assignment of the variable
+    blocks
+        .iter()
+        .rev()
+        .find(|block| matches!(block, Block::Assignment { name, .. } if name == variable))
+}
+
+fn find_function<'a>(blocks: &'a [Block], function: &str) -> Option<&'a Block> {
+    //! returns the top level definition of the function
+    blocks
+        .iter()
+        .find(|block| matches!(block, Block::Function { name, .. } if name == function))
+}
+
+fn append_to_array(lines: &mut Vec<String>, start: usize, end: usize, values: &[String]) {
+    //! takes the first and last line of an assignment and appends the values to the array
+    //! a value that isn't an array (e.g. source=file) is converted into one
+    let text = lines[start..=end].join("\n");
+    let Some((_, _, value)) = parse_assignment(&text) else {
+        return;
+    };
+    let value_start = text.len() - value.len();
+    let addition = values.join(" ");
+
+    let new_text = match value.strip_prefix('(') {
+        Some(array) => {
+            let close = value_start + 1 + array_end(array).unwrap_or(array.len());
+            let before = &text[..close];
+            let closing_line = &before[before.rfind('\n').map_or(0, |i| i + 1)..];
+
+            if closing_line.trim().is_empty() && before.contains('\n') {
+                // the ) is on its own line --> add a new line with the indentation of the
+                // previous entry
+                let previous = before.trim_end_matches([' ', '\t']).trim_end_matches('\n');
+                let previous_line = &previous[previous.rfind('\n').map_or(0, |i| i + 1)..];
+                let indent: String = previous_line
+                    .chars()
+                    .take_while(|c| c.is_whitespace())
+                    .collect();
+                // entries on the line of the assignment are aligned after the (
+                let indent = match previous_line.find('(') {
+                    Some(position) if previous_line == &text[..previous_line.len()] => {
+                        " ".repeat(position + 1)
+                    }
+                    _ => indent,
+                };
+                format!(
+                    "{}\n{}{}\n{}{}",
+                    previous,
+                    indent,
+                    addition,
+                    closing_line,
+                    &text[close..]
+                )
+            } else if before.trim_end().ends_with('(') {
+                format!("{}{}{}", before, addition, &text[close..])
+            } else {
+                format!("{} {}{}", before, addition, &text[close..])
+            }
+        }
+        None => {
+            // the rest of the line after the value (e.g. a comment) is kept
+            let value_length = value.split_whitespace().next().map_or(0, str::len);
+            format!(
+                "{}({} {}){}",
+                &text[..value_start],
+                &value[..value_length],
+                addition,
+                &value[value_length..]
+            )
+        }
+    };
+
+    lines.splice(start..=end, new_text.lines().map(str::to_string));
+}
+
+fn first_cd(lines: &[String], blocks: &[Block]) -> Option<String> {
+    //! returns the first cd command of build() or package() to find the directory of the sources
+    for function in ["build", "package"] {
+        if let Some(Block::Function { start, end, .. }) = find_function(blocks, function) {
+            for line in &lines[*start..=*end] {
+                // the function header and the brace can be on the line of the cd, e.g.
+                // build() { cd foo; make; }
+                let command = line
+                    .split(';')
+                    .map(|command| {
+                        command
+                            .rsplit_once("() {")
+                            .map_or(command, |(_, command)| command)
+                            .trim()
+                            .trim_start_matches('{')
+                            .trim()
+                    })
+                    .find(|command| command.starts_with("cd "));
+                if let Some(command) = command {
+                    let command = command.split("&&").next().unwrap_or(command).trim();
+                    return Some(command.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn array_values(value: &str) -> Vec<String> {
+    //! takes the value of an assignment and returns the entries without expanding variables
+    match value.strip_prefix('(') {
+        Some(array) => split_words(
+            &array[..array_end(array).unwrap_or(array.len())],
+            &Default::default(),
+        ),
+        None => split_words(value, &Default::default())
+            .into_iter()
+            .take(1)
+            .collect(),
+    }
+}
+
+fn extend_prepare(lines: &mut Vec<String>, start: usize, end: usize, commands: &[String]) {
+    //! takes the first and last line of prepare() and adds the commands in front of the closing
+    //! brace with the indentation of the function body
+    let closing = lines[end].clone();
+    if closing.trim_start().starts_with('}') {
+        let indent: String = match lines[start + 1..end]
+            .iter()
+            .find(|line| !line.trim().is_empty() && line.trim() != "{")
+        {
+            Some(line) => line.chars().take_while(|c| c.is_whitespace()).collect(),
+            None => "    ".to_string(),
+        };
+        let new_lines: Vec<String> = commands
+            .iter()
+            .map(|command| format!("{}{}", indent, command))
+            .collect();
+        lines.splice(end..end, new_lines);
+    } else if let Some(position) = closing.rfind('}') {
+        // prepare() { ...; } on one line
+        let inserted: String = commands
+            .iter()
+            .map(|command| format!("{}; ", command))
+            .collect();
+        lines[end] = format!(
+            "{}{}{}",
+            &closing[..position],
+            inserted,
+            &closing[position..]
+        );
+    }
+}
+
+fn in_sources(blocks: &[Block], patch: &PatchEntry) -> bool {
+    //! returns true if the patch is already part of the source array of the PKGBUILD
+    blocks
+        .iter()
+        .filter_map(|block| match block {
+            Block::Assignment { name, value, .. } if name == "source" => Some(array_values(value)),
+            _ => None,
+        })
+        .flatten()
+        .any(|source| source == patch.file || source.ends_with(&format!("/{}", patch.file)))
+}
+
+fn inject(
+    content: &str,
+    patches: &[PatchEntry],
+    command: impl Fn(usize, &PatchEntry) -> String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    //! takes the content of a PKGBUILD and the patches and returns the PKGBUILD with the patches
+    //! added to the source array and the command for every patch (with its index) at the end of
+    //! prepare()
+    //! for every checksum array of the sources a SKIP entry is added so that all arrays keep the
+    //! same length (the correct sums are set by updpkgsums before the build)
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let blocks = scan_blocks(&content.lines().collect::<Vec<&str>>());
+
+    // patches that are already part of the sources are not added twice
+    let new_patches: Vec<(usize, &PatchEntry)> = patches
+        .iter()
+        .enumerate()
+        .filter(|(_, patch)| !in_sources(&blocks, patch))
+        .collect();
+
+    if new_patches.is_empty() {
+        return Ok(content.to_string());
+    }
+
+    // changes of arrays as (first line, last line, entries to add)
+    let mut array_changes: Vec<(usize, usize, Vec<String>)> = Vec::new();
+    // assignments that are added at the end of the file
+    let mut appended_lines: Vec<String> = Vec::new();
+
+    let sources: Vec<String> = new_patches
+        .iter()
+        .map(|(_, patch)| shell_quote(&patch.file))
+        .collect();
+    match last_assignment(&blocks, "source") {
+        Some(Block::Assignment { start, end, .. }) => array_changes.push((*start, *end, sources)),
+        _ => appended_lines.push(format!("source+=({})", sources.join(" "))),
+    }
+
+    let skips = vec![shell_quote("SKIP"); new_patches.len()];
+    for checksum in CHECKSUM_ARRAYS {
+        match last_assignment(&blocks, checksum) {
+            Some(Block::Assignment { start, end, .. }) => {
+                array_changes.push((*start, *end, skips.clone()))
+            }
+            _ => {
+                // checksums that only exist for architecture specific sources need a generic
+                // array for the patches
+                let arch_specific = blocks.iter().any(|block| {
+                    matches!(block, Block::Assignment { name, .. }
+                        if name.starts_with(&format!("{}_", checksum)))
+                });
+                if arch_specific {
+                    appended_lines.push(format!("{}+=({})", checksum, skips.join(" ")));
+                }
+            }
+        }
+    }
+
+    let commands: Vec<String> = new_patches
+        .iter()
+        .map(|(index, patch)| command(*index, patch))
+        .collect();
+    let prepare = match find_function(&blocks, "prepare") {
+        Some(Block::Function { start, end, .. }) => Some((*start, *end)),
+        _ => None,
+    };
+
+    // all changes are applied from the end of the file so that the line numbers stay valid
+    array_changes.sort_by_key(|change| std::cmp::Reverse(change.0));
+    let mut prepare_extended = false;
+    for (start, end, values) in array_changes {
+        if let Some((prepare_start, prepare_end)) = prepare {
+            if prepare_end > end && !prepare_extended {
+                extend_prepare(&mut lines, prepare_start, prepare_end, &commands);
+                prepare_extended = true;
+            }
+        }
+        append_to_array(&mut lines, start, end, &values);
+    }
+
+    match prepare {
+        Some((prepare_start, prepare_end)) if !prepare_extended => {
+            extend_prepare(&mut lines, prepare_start, prepare_end, &commands)
+        }
+        Some(_) => {}
+        None => {
+            // without a prepare() the patches are applied in the directory that is used by
+            // build() or package()
+            let cd = first_cd(&lines, &blocks).unwrap_or("cd \"${srcdir}\"".to_string());
+            lines.push(String::new());
+            lines.push("prepare() {".to_string());
+            lines.push(format!("    {}", cd));
+            for command in &commands {
+                lines.push(format!("    {}", command));
+            }
+            lines.push("}".to_string());
+        }
+    }
+
+    if !appended_lines.is_empty() {
+        // bash only needs the variables to be defined before the functions are run
+        lines.push(String::new());
+        lines.extend(appended_lines);
+    }
+
+    let mut modified_content = lines.join("\n");
+    modified_content.push('\n');
+
+    Ok(modified_content)
+}
+
+pub fn inject_patches(
+    content: &str,
+    patches: &[PatchEntry],
+) -> Result<String, Box<dyn std::error::Error>> {
+    //! takes the content of a PKGBUILD and the patches and returns the PKGBUILD with the patches
+    //! added to the source array and applied at the end of prepare()
+    inject(content, patches, |_, patch| patch.command())
+}
+
+pub fn modify_pkgbuild(
+    file: &str,
+    patches: &[PatchEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    //! takes the PKGBUILD file and the patches and adds the patches to the file
+    let content = read_to_string(file)?;
+    write(file, inject_patches(&content, patches)?)?;
+    Ok(())
+}
+
+fn parse_dry_run(exit_code: &str, output: &str) -> PatchStatus {
+    //! takes the exit code and the output of patch --dry-run and returns the status of the patch
+    if exit_code.trim() == "0" {
+        if output.contains("with fuzz") {
+            PatchStatus::AppliesWithFuzz
+        } else {
+            PatchStatus::Applies
+        }
+    } else if output.contains("Reversed (or previously applied) patch detected") {
+        PatchStatus::Reversed
+    } else {
+        // e.g. "2 out of 5 hunks FAILED -- saving rejects to file src/main.c.rej"
+        let re = Regex::new(r"(\d+) out of \d+ hunks? FAILED").unwrap();
+        let rejected_hunks = re
+            .captures_iter(output)
+            .filter_map(|captures| captures[1].parse::<u32>().ok())
+            .sum();
+        PatchStatus::Fails(rejected_hunks)
+    }
+}
+
+fn preflight_command(package_name: &str, log_dir: &str, chroot: Option<&Chroot>) -> String {
+    //! returns the command that prepares the sources with the preflight PKGBUILD
+    //! prepare() can need the makedepends (e.g. autoreconf), so they are installed like for the
+    //! build: in a copy of the chroot or on the host and removed afterwards
+    //! the chroot writes the results of the dry runs to the log directory that is mounted into it
+    let makepkg_arguments = "--nobuild --skippgpcheck --noconfirm -p PKGBUILD.preflight";
+    match chroot {
+        Some(chroot) => format!(
+            "makechrootpkg -c -r {} -l {}-preflight -d {} {} -- {}",
+            chroot.dir,
+            package_name,
+            log_dir,
+            chroot.bind_arguments("-D "),
+            makepkg_arguments
+        ),
+        None => format!("makepkg --syncdeps --rmdeps {}", makepkg_arguments),
+    }
+}
+
+pub fn preflight(
+    package_name: &str,
+    pkg_build_dir: &str,
+    patches: &[PatchEntry],
+    srcdest: &str,
+    chroot: Option<&Chroot>,
+) -> Result<Vec<PatchReport>, Box<dyn std::error::Error>> {
+    //! takes the package name, the src-directory of the build files, the patches, the shared
+    //! source directory and the chroot of the builds and checks if the patches apply
+    //! the sources are extracted and prepared by makepkg --nobuild with a copy of the PKGBUILD that
+    //! runs patch --dry-run for every patch at the end of prepare()
+    //! patches that are already part of the sources are applied by the PKGBUILD itself and aren't
+    //! checked
+    //! returns a report per patch
+    let pkg_build_dir = canonicalize(pkg_build_dir)?.to_string_lossy().to_string();
+    let log_dir = format!("{}/preflight", pkg_build_dir);
+    let preflight_pkgbuild = format!("{}/PKGBUILD.preflight", pkg_build_dir);
+
+    let _ = remove_dir_all(&log_dir);
+    create_dir_all(&log_dir)?;
+
+    let content = read_to_string(format!("{}/PKGBUILD", pkg_build_dir))?;
+    let blocks = scan_blocks(&content.lines().collect::<Vec<&str>>());
+    write(
+        &preflight_pkgbuild,
+        inject(&content, patches, |index, patch| {
+            patch.preflight_command(index, &log_dir)
+        })?,
+    )?;
+
+    let makepkg_output = Command::new("bash")
+        .arg("-c")
+        .arg(preflight_command(package_name, &log_dir, chroot))
+        .current_dir(&pkg_build_dir)
+        .env("SRCDEST", srcdest)
+        .output()?;
+    let makepkg_log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&makepkg_output.stdout),
+        String::from_utf8_lossy(&makepkg_output.stderr)
+    );
+
+    let mut reports: Vec<PatchReport> = Vec::new();
+    for (index, patch) in patches.iter().enumerate() {
+        if in_sources(&blocks, patch) {
+            reports.push(PatchReport {
+                file: patch.file.clone(),
+                status: PatchStatus::InSources,
+                output: String::new(),
+            });
+            continue;
+        }
+        let output = read_to_string(format!("{}/{}.log", log_dir, index)).unwrap_or_default();
+        let report = match read_to_string(format!("{}/{}.status", log_dir, index)) {
+            Ok(exit_code) => PatchReport {
+                file: patch.file.clone(),
+                status: parse_dry_run(&exit_code, &output),
+                output,
+            },
+            Err(_) => PatchReport {
+                file: patch.file.clone(),
+                status: PatchStatus::NotChecked,
+                output: makepkg_log.clone(),
+            },
+        };
+        reports.push(report);
+    }
+
+    let _ = remove_file(&preflight_pkgbuild);
+    let _ = remove_dir_all(&log_dir);
+
+    Ok(reports)
+}
+
+pub fn print_report(package_name: &str, reports: &[PatchReport]) {
+    //! prints the result of the dry run of every patch and the output of the failed ones
+    println!("{}", format!("Patch check for {}:", package_name).blue());
+    for report in reports {
+        match report.status {
+            PatchStatus::Applies => {
+                println!("{}", format!("  {}: applies", report.file).green())
+            }
+            PatchStatus::AppliesWithFuzz => println!(
+                "{}",
+                format!("  {}: applies with fuzz", report.file).yellow()
+            ),
+            PatchStatus::Fails(rejected_hunks) => println!(
+                "{}",
+                format!(
+                    "  {}: fails with {} rejected hunks",
+                    report.file, rejected_hunks
+                )
+                .red()
+            ),
+            PatchStatus::Reversed => println!(
+                "{}",
+                format!(
+                    "  {}: fails, the patch is already applied or reversed",
+                    report.file
+                )
+                .red()
+            ),
+            PatchStatus::InSources => println!(
+                "{}",
+                format!("  {}: already in the sources of the PKGBUILD", report.file).green()
+            ),
+            PatchStatus::NotChecked => println!(
+                "{}",
+                format!(
+                    "  {}: not checked, the sources couldn't be prepared",
+                    report.file
+                )
+                .red()
+            ),
+        }
+        if !matches!(report.status, PatchStatus::Applies | PatchStatus::InSources) {
+            // only the end of the output is relevant, makepkg prints the complete download
+            let lines: Vec<&str> = report.output.lines().collect();
+            for line in &lines[lines.len().saturating_sub(20)..] {
+                println!("    {}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // same corpus as the pkgbuild tests, foo-bin, libfoo and heredoc are synthetic
+    const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pkgbuild");
+
+    fn corpus(package: &str) -> String {
+        read_to_string(format!("{}/{}/PKGBUILD", CORPUS, package)).unwrap()
+    }
+
+    fn patches() -> Vec<PatchEntry> {
+        let mut second = PatchEntry::new("fix-build.patch");
+        second.directory = "src".to_string();
+        vec![PatchEntry::new("0001-first.patch"), second]
+    }
+
+    fn write_temporary(name: &str, content: &str) -> String {
+        let file = std::env::temp_dir()
+            .join(format!("nompac-test-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        write(&file, content).unwrap();
+        file
+    }
+
+    fn bash_syntax_ok(name: &str, content: &str) -> bool {
+        let file = write_temporary(name, content);
+        let status = Command::new("bash").args(["-n", &file]).status().unwrap();
+        let _ = remove_file(&file);
+        status.success()
+    }
+
+    fn array_lengths(name: &str, content: &str) -> HashMap<String, usize> {
+        //! sources the PKGBUILD with bash and returns the length of the source and checksum arrays
+        let file = write_temporary(name, content);
+        let mut arrays = vec!["source".to_string()];
+        arrays.extend(CHECKSUM_ARRAYS.iter().map(|array| array.to_string()));
+        let arrays: Vec<String> = arrays
+            .iter()
+            .flat_map(|array| [array.clone(), format!("{}_x86_64", array)])
+            .collect();
+        let script = format!(
+            "source {} > /dev/null 2>&1; for name in {}; do declare -p $name > /dev/null 2>&1 && eval \"echo $name \\${{#$name[@]}}\"; done",
+            file,
+            arrays.join(" ")
+        );
+        let output = Command::new("bash").args(["-c", &script]).output().unwrap();
+        let _ = remove_file(&file);
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, length)| (name.to_string(), length.parse().unwrap()))
+            .collect()
+    }
+
+    fn assert_consistent(name: &str, original: &str, modified: &str) {
+        //! checks that the modified PKGBUILD is valid bash, that the patches were added to the
+        //! sources and that every checksum array still has one entry per source on x86_64
+        assert!(bash_syntax_ok(name, modified), "{}:\n{}", name, modified);
+        let before = array_lengths(name, original);
+        let after = array_lengths(name, modified);
+        let length = |arrays: &HashMap<String, usize>, array: &str| {
+            arrays.get(array).copied().unwrap_or(0)
+                + arrays
+                    .get(&format!("{}_x86_64", array))
+                    .copied()
+                    .unwrap_or(0)
+        };
+        let sources = length(&after, "source");
+        assert_eq!(sources, length(&before, "source") + 2, "{}", name);
+        for checksum in CHECKSUM_ARRAYS {
+            if length(&before, checksum) > 0 {
+                assert_eq!(length(&after, checksum), sources, "{} {}", name, checksum);
+            }
+        }
+    }
+
+    fn prepare_body(content: &str) -> Vec<String> {
+        //! returns the lines of prepare() in the content
+        let lines: Vec<&str> = content.lines().collect();
+        let (start, end) = scan_blocks(&lines)
+            .into_iter()
+            .find_map(|block| match block {
+                Block::Function { name, start, end } if name == "prepare" => Some((start, end)),
+                _ => None,
+            })
+            .expect("no prepare()");
+        lines[start..=end]
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    fn assert_patches_at_end_of_prepare(name: &str, modified: &str) {
+        let prepare = prepare_body(modified);
+        let commands: Vec<String> = patches().iter().map(|patch| patch.command()).collect();
+        let count = prepare.len();
+        assert_eq!(prepare[count - 1].trim(), "}", "{}:\n{}", name, modified);
+        assert_eq!(
+            prepare[count - 3].trim(),
+            commands[0],
+            "{}:\n{}",
+            name,
+            modified
+        );
+        assert_eq!(
+            prepare[count - 2].trim(),
+            commands[1],
+            "{}:\n{}",
+            name,
+            modified
+        );
+    }
+
+    #[test]
+    fn patch_conditions() {
+        // (condition, version, expected)
+        let cases = [
+            ("", "1.0-1", true),
+            (">=0.18, <0.19", "0.18.2-1", true),
+            (">=0.18, <0.19", "0.19.0-1", false),
+            (">=0.18, <0.19", "0.17.4-3", false),
+            // constraints without an epoch ignore the epoch of the version
+            (">=0.18, <0.19", "1:0.18.2-1", true),
+            (">=1:0.18", "0.18.2-1", false),
+            (">=1:0.18", "1:0.18.2-1", true),
+            ("=1.2-3", "1.2-3", true),
+            ("==1.2-3", "1.2-4", false),
+            // without a pkgrel only the pkgver is compared
+            ("1.2", "1.2-3", true),
+            ("!=1.2", "1.3-1", true),
+            ("!=1.2", "1.2-1", false),
+            ("<=1.2", "1.2rc1-1", true),
+            ("<1.2", "1.2-1", false),
+            (">1.2", "1.10-1", true),
+            ("~1.2", "1.2-1", false),
+        ];
+        for (condition, version, expected) in cases {
+            let mut patch = PatchEntry::new("fix.patch");
+            patch.condition = condition.to_string();
+            assert_eq!(
+                patch.matches(version),
+                expected,
+                "{} with {}",
+                condition,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn select_patches_of_config() {
+        let config: HashMap<String, Vec<PatchConfig>> = toml::from_str(
+            r#"
+            wlroots = [
+              "nvidia.patch",
+              { file = "render.patch", strip = 2, directory = "wlroots-0.18.2", condition = ">=0.18, <0.19", order = 1 },
+              { file = "old.patch", condition = "<0.18" },
+              { file = "first.patch", order = -1 },
+              "second.patch",
+            ]
+            "#,
+        )
+        .unwrap();
+        let patches = &config["wlroots"];
+        assert!(matches!(&patches[0], PatchConfig::File(file) if file == "nvidia.patch"));
+        assert!(matches!(&patches[1], PatchConfig::Table(_)));
+
+        let selected = select_patches(patches, "0.18.2-1");
+        let files: Vec<&str> = selected.iter().map(|patch| patch.file.as_str()).collect();
+        // sorted by order, patches with the same order keep the order of the config
+        assert_eq!(
+            files,
+            vec![
+                "first.patch",
+                "nvidia.patch",
+                "second.patch",
+                "render.patch"
+            ]
+        );
+
+        // plain file names and tables without settings use the defaults
+        for patch in &selected[..3] {
+            assert_eq!(patch.strip, 1);
+            assert_eq!(patch.directory, "");
+        }
+        assert_eq!(selected[3].strip, 2);
+        assert_eq!(selected[3].directory, "wlroots-0.18.2");
+        assert_eq!(
+            selected[3].command(),
+            "patch -Np2 -d \"${srcdir}/wlroots-0.18.2\" -i \"${srcdir}/render.patch\""
+        );
+        assert_eq!(
+            selected[0].command(),
+            "patch -Np1 -i \"${srcdir}/first.patch\""
+        );
+
+        let files: Vec<String> = select_patches(patches, "0.17.0-1")
+            .into_iter()
+            .map(|patch| patch.file)
+            .collect();
+        assert_eq!(
+            files,
+            vec!["first.patch", "nvidia.patch", "old.patch", "second.patch"]
+        );
+    }
+
+    #[test]
+    fn inject_single_line_source_without_prepare() {
+        let original = corpus("nano");
+        let modified = inject_patches(&original, &patches()).unwrap();
+        assert_consistent("nano", &original, &modified);
+        assert!(modified.contains(
+            "source=(https://www.nano-editor.org/dist/v${pkgver%.*}/${pkgname}-${pkgver}.tar.xz{,.asc} '0001-first.patch' 'fix-build.patch')"
+        ));
+        // the new prepare() changes into the directory of build()
+        let prepare = prepare_body(&modified);
+        assert_eq!(prepare[1].trim(), "cd ${pkgname}-${pkgver}");
+        assert_patches_at_end_of_prepare("nano", &modified);
+    }
+
+    #[test]
+    fn inject_closing_parenthesis_on_own_line() {
+        let original = corpus("linux");
+        let modified = inject_patches(&original, &patches()).unwrap();
+        assert_consistent("linux", &original, &modified);
+        assert!(modified.contains(
+            "  config  # the main kernel config file\n  '0001-first.patch' 'fix-build.patch'\n)"
+        ));
+        assert!(modified.contains("6071'\n        'SKIP' 'SKIP'\n)"));
+        assert_patches_at_end_of_prepare("linux", &modified);
+    }
+
+    #[test]
+    fn inject_architecture_specific_sources() {
+        let original = corpus("foo-bin");
+        let modified = inject_patches(&original, &patches()).unwrap();
+        assert_consistent("foo-bin", &original, &modified);
+        assert!(modified.contains("source+=('0001-first.patch' 'fix-build.patch')"));
+        assert!(modified.contains("sha256sums+=('SKIP' 'SKIP')"));
+        // without a cd in build() or package(), the patches are applied in $srcdir
+        assert_eq!(prepare_body(&modified)[1].trim(), "cd \"${srcdir}\"");
+        assert_patches_at_end_of_prepare("foo-bin", &modified);
+    }
+
+    #[test]
+    fn inject_one_line_prepare() {
+        let original = corpus("libfoo");
+        let modified = inject_patches(&original, &patches()).unwrap();
+        assert_consistent("libfoo", &original, &modified);
+        let commands: Vec<String> = patches().iter().map(|patch| patch.command()).collect();
+        assert!(modified.contains(&format!(
+            "prepare() {{ cd \"$pkgname-$pkgver\"; autoreconf -fi; {}; {}; }}",
+            commands[0], commands[1]
+        )));
+    }
+
+    #[test]
+    fn inject_prepare_with_heredocs() {
+        let original = corpus("heredoc");
+        let modified = inject_patches(&original, &patches()).unwrap();
+        assert_consistent("heredoc", &original, &modified);
+        // the bodies of the here-documents are unchanged
+        assert!(modified.contains("    \"prefix\": \"/usr\",\n}\nEOF\n"));
+        assert!(modified.contains("\t[options]\n\t}\n\tEND\n"));
+        assert!(modified.contains("exec python -m foo_launcher \"$@\"\n}\nEOF\n}\n"));
+        assert_patches_at_end_of_prepare("heredoc", &modified);
+    }
+
+    #[test]
+    fn inject_heredoc_with_closing_brace() {
+        let original = "pkgname=foo\npkgver=1\npkgrel=1\nsource=(foo.tar.gz)\nsha256sums=('SKIP')\n\nprepare() {\n  cat <<EOF > f\n}\nEOF\n  cd foo\n}\n";
+        let modified = inject_patches(original, &patches()).unwrap();
+        assert_consistent("heredoc-brace", original, &modified);
+        assert!(modified.contains("  cat <<EOF > f\n}\nEOF\n  cd foo\n  patch "));
+        assert_patches_at_end_of_prepare("heredoc-brace", &modified);
+    }
+
+    #[test]
+    fn inject_skips_existing_patches() {
+        let original = corpus("nano").replace(
+            ".tar.xz{,.asc})",
+            ".tar.xz{,.asc} 0001-first.patch fix-build.patch)",
+        );
+        assert_eq!(inject_patches(&original, &patches()).unwrap(), original);
+    }
+
+    #[test]
+    fn dry_run_results() {
+        // output of patch --dry-run -Np1 -i
+        assert_eq!(
+            parse_dry_run("0\n", "checking file f.txt\n"),
+            PatchStatus::Applies
+        );
+        assert_eq!(
+            parse_dry_run(
+                "0\n",
+                "checking file f.txt\nHunk #1 succeeded at 3 (offset 2 lines).\n"
+            ),
+            PatchStatus::Applies
+        );
+        assert_eq!(
+            parse_dry_run(
+                "0\n",
+                "checking file f.txt\nHunk #1 succeeded at 1 with fuzz 2.\n"
+            ),
+            PatchStatus::AppliesWithFuzz
+        );
+        assert_eq!(
+            parse_dry_run(
+                "1\n",
+                "checking file f.txt\nHunk #1 FAILED at 1.\n1 out of 1 hunk FAILED\nchecking file src/main.c\nHunk #2 FAILED at 40.\nHunk #3 FAILED at 52.\n2 out of 3 hunks FAILED\n"
+            ),
+            PatchStatus::Fails(3)
+        );
+        assert_eq!(
+            parse_dry_run(
+                "1\n",
+                "checking file f.txt\nReversed (or previously applied) patch detected!  Skipping patch.\n1 out of 1 hunk ignored\n"
+            ),
+            PatchStatus::Reversed
+        );
+        assert_eq!(
+            parse_dry_run("2\n", "can't find file to patch at input line 3\n"),
+            PatchStatus::Fails(0)
+        );
+    }
+
+    #[test]
+    fn patches_in_sources() {
+        let content =
+            corpus("nano").replace(".tar.xz{,.asc})", ".tar.xz{,.asc} fixes/0001-first.patch)");
+        let blocks = scan_blocks(&content.lines().collect::<Vec<&str>>());
+        let patches = patches();
+        assert!(in_sources(&blocks, &patches[0]));
+        assert!(!in_sources(&blocks, &patches[1]));
+    }
+
+    #[test]
+    fn preflight_resolves_dependencies() {
+        // the makedepends are needed by prepare(), so --nodeps must not be used
+        let host = preflight_command("libfoo", "/build/preflight", None);
+        assert_eq!(
+            host,
+            "makepkg --syncdeps --rmdeps --nobuild --skippgpcheck --noconfirm -p PKGBUILD.preflight"
+        );
+
+        let chroot = Chroot {
+            dir: "/build/chroot".to_string(),
+            local_repo_dirs: vec!["/repo/patched".to_string()],
+        };
+        assert_eq!(
+            preflight_command("libfoo", "/build/preflight", Some(&chroot)),
+            "makechrootpkg -c -r /build/chroot -l libfoo-preflight -d /build/preflight -D /repo/patched -- --nobuild --skippgpcheck --noconfirm -p PKGBUILD.preflight"
+        );
+    }
+}
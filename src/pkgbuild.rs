@@ -0,0 +1,768 @@
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::Path;
+
+// struct to contain the metadata of a PKGBUILD or .SRCINFO
+#[derive(Debug, Default, Clone)]
+pub struct PkgbuildInfo {
+    pub pkgbase: String,
+    pub pkgnames: Vec<String>,
+    pub epoch: String,
+    pub pkgver: String,
+    pub pkgrel: String,
+    pub depends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub provides: Vec<String>,
+}
+
+impl PkgbuildInfo {
+    pub fn version(&self) -> String {
+        //! returns the full version epoch:pkgver-pkgrel, the epoch is omitted if it isn't set
+        if self.epoch.is_empty() || self.epoch == "0" {
+            format!("{}-{}", self.pkgver, self.pkgrel)
+        } else {
+            format!("{}:{}-{}", self.epoch, self.pkgver, self.pkgrel)
+        }
+    }
+}
+
+fn is_arch_key(key: &str, base_key: &str) -> bool {
+    //! returns true if the key is the base key or its variant for the current architecture
+    //! e.g. source and source_x86_64
+    key == base_key || key == format!("{}_{}", base_key, std::env::consts::ARCH)
+}
+
+fn add_entry(info: &mut PkgbuildInfo, key: &str, values: Vec<String>) {
+    //! takes a key and its values and stores them in the metadata
+    let first = values.first().cloned().unwrap_or_default();
+    match key {
+        "pkgbase" => info.pkgbase = first,
+        "pkgname" => info.pkgnames = values,
+        "epoch" => info.epoch = first,
+        "pkgver" => info.pkgver = first,
+        "pkgrel" => info.pkgrel = first,
+        _ if is_arch_key(key, "depends") => info.depends.extend(values),
+        _ if is_arch_key(key, "makedepends") => info.makedepends.extend(values),
+        _ if is_arch_key(key, "provides") => info.provides.extend(values),
+        _ => {}
+    }
+}
+
+fn finish(mut info: PkgbuildInfo) -> Result<PkgbuildInfo, Box<dyn std::error::Error>> {
+    //! fills the pkgbase if it wasn't defined and checks that all required fields exist
+    if info.pkgnames.is_empty() && !info.pkgbase.is_empty() {
+        info.pkgnames.push(info.pkgbase.clone());
+    }
+    if info.pkgbase.is_empty() {
+        info.pkgbase = info.pkgnames.first().cloned().unwrap_or_default();
+    }
+    if info.pkgbase.is_empty() || info.pkgver.is_empty() || info.pkgrel.is_empty() {
+        return Err("pkgname, pkgver or pkgrel missing".into());
+    }
+    Ok(info)
+}
+
+pub fn parse_srcinfo(content: &str) -> Result<PkgbuildInfo, Box<dyn std::error::Error>> {
+    //! takes the content of a .SRCINFO file and returns the metadata
+    //! every line is a key = value pair, arrays are defined by repeating the key
+    let mut info = PkgbuildInfo::default();
+    let mut arrays: HashMap<String, Vec<String>> = HashMap::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim().to_string());
+        match key {
+            "pkgbase" | "epoch" | "pkgver" | "pkgrel" => add_entry(&mut info, key, vec![value]),
+            "pkgname" => info.pkgnames.push(value),
+            _ => arrays.entry(key.to_string()).or_default().push(value),
+        }
+    }
+
+    // arrays of the split packages are merged with the ones of the pkgbase
+    for (key, mut values) in arrays {
+        let mut seen: HashSet<String> = HashSet::new();
+        values.retain(|value| seen.insert(value.clone()));
+        add_entry(&mut info, &key, values);
+    }
+
+    finish(info)
+}
+
+fn expand_variable(name: &str, variables: &HashMap<String, Vec<String>>) -> Option<String> {
+    //! takes a variable name (optionally with [@] or [0]) and returns its value
+    //! only plain variables are expanded, parameter expansions with modifiers return None
+    let (name, index) = match name.split_once('[') {
+        Some((name, index)) => (name, index.trim_end_matches(']')),
+        None => (name, "0"),
+    };
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let values = variables.get(name)?;
+    match index {
+        "@" | "*" => Some(values.join(" ")),
+        _ => values.get(index.parse::<usize>().ok()?).cloned(),
+    }
+}
+
+pub fn split_words(text: &str, variables: &HashMap<String, Vec<String>>) -> Vec<String> {
+    //! splits bash text into words and handles quotes, escapes, comments and variable references
+    let chars: Vec<char> = text.chars().collect();
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '#' if !in_word => {
+                // comment until the end of the line
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                i += 1;
+                while i < chars.len() && chars[i] != '\'' {
+                    word.push(chars[i]);
+                    i += 1;
+                }
+            }
+            '"' => {
+                in_word = true;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        i += 1;
+                        word.push(chars[i]);
+                    } else if chars[i] == '$' {
+                        i = expand_at(&chars, i, variables, &mut word);
+                        continue;
+                    } else {
+                        word.push(chars[i]);
+                    }
+                    i += 1;
+                }
+            }
+            '\\' => {
+                // a backslash before a newline continues the line and doesn't start a word
+                if i + 1 < chars.len() && chars[i + 1] != '\n' {
+                    in_word = true;
+                    word.push(chars[i + 1]);
+                }
+                i += 1;
+            }
+            '$' => {
+                in_word = true;
+                i = expand_at(&chars, i, variables, &mut word);
+                continue;
+            }
+            _ => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+        i += 1;
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+fn expand_at(
+    chars: &[char],
+    start: usize,
+    variables: &HashMap<String, Vec<String>>,
+    word: &mut String,
+) -> usize {
+    //! expands the variable reference starting with $ at start and appends it to the word
+    //! returns the position after the reference
+    //! references that can't be expanded are kept as written
+    let mut i = start + 1;
+    if i < chars.len() && chars[i] == '{' {
+        let mut depth = 1;
+        let mut end = i + 1;
+        while end < chars.len() {
+            match chars[end] {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            end += 1;
+        }
+        let name: String = chars[i + 1..end.min(chars.len())].iter().collect();
+        match expand_variable(&name, variables) {
+            Some(value) => word.push_str(&value),
+            None => word.extend(&chars[start..(end + 1).min(chars.len())]),
+        }
+        return end + 1;
+    }
+
+    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+        i += 1;
+    }
+    let name: String = chars[start + 1..i].iter().collect();
+    if name.is_empty() {
+        word.push('$');
+    } else {
+        match expand_variable(&name, variables) {
+            Some(value) => word.push_str(&value),
+            None => word.extend(&chars[start..i]),
+        }
+    }
+    i
+}
+
+pub fn array_end(text: &str) -> Option<usize> {
+    //! takes text starting after the opening ( of an array and returns the position of the closing
+    //! ) while ignoring quoted parts and comments
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut quote: Option<char> = None;
+    let mut word_start = true;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        match quote {
+            Some(q) => {
+                if c == '\\' && q == '"' {
+                    i += 1;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '\\' => i += 1,
+                '#' if word_start => {
+                    while i < chars.len() && chars[i].1 != '\n' {
+                        i += 1;
+                    }
+                    continue;
+                }
+                ')' => return Some(position),
+                _ => {}
+            },
+        }
+        word_start = c.is_whitespace() || c == '(';
+        i += 1;
+    }
+
+    None
+}
+
+pub fn brace_delta(line: &str) -> i32 {
+    //! returns the number of opened minus closed braces in a line outside of quotes and comments
+    let mut delta = 0;
+    // depth of open ${...} parameter expansions
+    let mut expansions = 0;
+    let mut quote: Option<char> = None;
+    let mut previous = ' ';
+    let mut escaped = false;
+
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+            previous = c;
+            continue;
+        }
+        match quote {
+            Some(q) => {
+                if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '#' if previous.is_whitespace() => break,
+                // ${...} is a parameter expansion and not a block
+                '{' if previous == '$' => expansions += 1,
+                '}' if expansions > 0 => expansions -= 1,
+                '{' => delta += 1,
+                '}' => delta -= 1,
+                _ => {}
+            },
+        }
+        previous = c;
+    }
+
+    delta
+}
+
+fn heredoc_delimiters(line: &str) -> Vec<(String, bool)> {
+    //! returns the delimiters of the here-documents that start in a line outside of quotes and
+    //! comments, and true if leading tabs are stripped from the body (<<-)
+    //! here-strings (<<<) and shifts in arithmetic expressions ($((1 << 2))) are skipped
+    let chars: Vec<char> = line.chars().collect();
+    let mut delimiters: Vec<(String, bool)> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut previous = ' ';
+    let mut escaped = false;
+    // depth of open (( arithmetic expressions
+    let mut arithmetic = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if escaped {
+            escaped = false;
+            previous = c;
+            continue;
+        }
+        match quote {
+            Some(q) => {
+                if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '#' if previous.is_whitespace() => break,
+                '(' if previous == '(' => arithmetic += 1,
+                ')' if previous == ')' && arithmetic > 0 => arithmetic -= 1,
+                '<' if arithmetic == 0 && chars.get(i) == Some(&'<') => {
+                    i += 1;
+                    if chars.get(i) == Some(&'<') {
+                        i += 1;
+                        previous = '<';
+                        continue;
+                    }
+                    let strip_tabs = chars.get(i) == Some(&'-');
+                    if strip_tabs {
+                        i += 1;
+                    }
+                    while i < chars.len() && (chars[i] == ' ' || chars[i] == '\t') {
+                        i += 1;
+                    }
+                    // the delimiter can be quoted, e.g. <<'EOF' or <<"EOF"
+                    let mut delimiter = String::new();
+                    let mut delimiter_quote: Option<char> = None;
+                    while i < chars.len() {
+                        let d = chars[i];
+                        match delimiter_quote {
+                            Some(q) if d == q => delimiter_quote = None,
+                            Some(_) => delimiter.push(d),
+                            None => match d {
+                                '\'' | '"' => delimiter_quote = Some(d),
+                                '\\' => {}
+                                d if d.is_whitespace() || ";|&<>()".contains(d) => break,
+                                d => delimiter.push(d),
+                            },
+                        }
+                        i += 1;
+                    }
+                    if !delimiter.is_empty() {
+                        delimiters.push((delimiter, strip_tabs));
+                    }
+                    previous = ' ';
+                    continue;
+                }
+                _ => {}
+            },
+        }
+        previous = c;
+    }
+
+    delimiters
+}
+
+fn heredoc_end(lines: &[&str], next: usize, line: &str) -> usize {
+    //! takes the line and the index of the following line and returns the index of the first
+    //! line after the bodies of the here-documents that start in the line
+    let mut i = next;
+    for (delimiter, strip_tabs) in heredoc_delimiters(line) {
+        while i < lines.len() {
+            let body_line = if strip_tabs {
+                lines[i].trim_start_matches('\t')
+            } else {
+                lines[i]
+            };
+            i += 1;
+            if body_line == delimiter {
+                break;
+            }
+        }
+    }
+    i
+}
+
+pub fn parse_assignment(line: &str) -> Option<(String, bool, String)> {
+    //! takes a line and returns the variable name, true if it is an append (+=) and the value text
+    //! if the line is a top level variable assignment
+    let line = line.trim_start();
+    let name_length = line
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .count();
+    if name_length == 0 || line.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let (name, rest) = line.split_at(name_length);
+    if let Some(value) = rest.strip_prefix("+=") {
+        Some((name.to_string(), true, value.to_string()))
+    } else {
+        rest.strip_prefix('=')
+            .map(|value| (name.to_string(), false, value.to_string()))
+    }
+}
+
+// top level block of a PKGBUILD with the index of its first and last line
+#[derive(Debug, Clone)]
+pub enum Block {
+    // variable assignment, value contains the text after the = (including all lines of an array)
+    Assignment {
+        name: String,
+        append: bool,
+        value: String,
+        start: usize,
+        end: usize,
+    },
+    Function {
+        name: String,
+        start: usize,
+        end: usize,
+    },
+}
+
+fn function_name(line: &str) -> Option<String> {
+    //! returns the name of the function if the line starts a function definition
+    //! e.g. prepare() {, package_foo () or function build {
+    let re = Regex::new(
+        r"^\s*(?:function\s+([A-Za-z0-9_.:-]+)\s*(?:\(\s*\))?|([A-Za-z0-9_.:-]+)\s*\(\s*\))\s*(?:\{.*)?$",
+    )
+    .ok()?;
+    let captures = re.captures(line)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .map(|name| name.as_str().to_string())
+}
+
+pub fn scan_blocks(lines: &[&str]) -> Vec<Block> {
+    //! takes the lines of a PKGBUILD and returns all top level assignments and functions
+    //! arrays can span multiple lines and the end of functions is found by counting braces
+    //! the bodies of here-documents are skipped since their braces don't belong to the code
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let start = i;
+        i += 1;
+
+        if depth > 0 {
+            depth += brace_delta(line);
+            i = heredoc_end(lines, i, line);
+            continue;
+        }
+
+        if let Some((name, append, value)) = parse_assignment(line) {
+            let mut value = value;
+            if value.starts_with('(') {
+                // arrays can span multiple lines
+                while array_end(&value[1..]).is_none() && i < lines.len() {
+                    value.push('\n');
+                    value.push_str(lines[i]);
+                    i += 1;
+                }
+            }
+            blocks.push(Block::Assignment {
+                name,
+                append,
+                value,
+                start,
+                end: i - 1,
+            });
+            continue;
+        }
+
+        if let Some(name) = function_name(line) {
+            // the opening brace can be on the next line
+            let mut opened = line.contains('{');
+            let mut function_depth = brace_delta(line);
+            i = heredoc_end(lines, i, line);
+            while (!opened || function_depth > 0) && i < lines.len() {
+                opened = opened || lines[i].contains('{');
+                function_depth += brace_delta(lines[i]);
+                i = heredoc_end(lines, i + 1, lines[i]);
+            }
+            blocks.push(Block::Function {
+                name,
+                start,
+                end: i - 1,
+            });
+            continue;
+        }
+
+        // other blocks
+        depth += brace_delta(line);
+        i = heredoc_end(lines, i, line);
+    }
+
+    blocks
+}
+
+pub fn parse_pkgbuild(content: &str) -> Result<PkgbuildInfo, Box<dyn std::error::Error>> {
+    //! takes the content of a PKGBUILD and returns the metadata
+    //! only top level assignments are evaluated, the content of functions is skipped
+    let mut variables: HashMap<String, Vec<String>> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+    let lines: Vec<&str> = content.lines().collect();
+
+    for block in scan_blocks(&lines) {
+        let Block::Assignment {
+            name,
+            append,
+            value,
+            ..
+        } = block
+        else {
+            continue;
+        };
+
+        let values = if let Some(array) = value.strip_prefix('(') {
+            let end = array_end(array).unwrap_or(array.len());
+            split_words(&array[..end], &variables)
+        } else {
+            split_words(&value, &variables)
+                .into_iter()
+                .take(1)
+                .collect()
+        };
+
+        if !order.contains(&name) {
+            order.push(name.clone());
+        }
+        if append {
+            variables.entry(name).or_default().extend(values);
+        } else {
+            variables.insert(name, values);
+        }
+    }
+
+    let mut info = PkgbuildInfo::default();
+    for name in order {
+        add_entry(
+            &mut info,
+            &name,
+            variables.remove(&name).unwrap_or_default(),
+        );
+    }
+
+    finish(info)
+}
+
+pub fn read_package_info(package_dir: &str) -> Result<PkgbuildInfo, Box<dyn std::error::Error>> {
+    //! takes the directory of a package and returns the metadata
+    //! the .SRCINFO is preferred if it exists, otherwise the PKGBUILD is parsed
+    let srcinfo = Path::new(package_dir).join(".SRCINFO");
+    if srcinfo.is_file() {
+        return parse_srcinfo(&read_to_string(srcinfo)?);
+    }
+    parse_pkgbuild(&read_to_string(Path::new(package_dir).join("PKGBUILD"))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nano and linux are based on upstream PKGBUILDs, foo-bin, libfoo and heredoc are synthetic
+    // ones for patterns the upstream ones don't cover (see the README of the corpus)
+    const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pkgbuild");
+
+    fn corpus(package: &str) -> String {
+        read_to_string(format!("{}/{}/PKGBUILD", CORPUS, package)).unwrap()
+    }
+
+    fn function_lines(lines: &[&str], function: &str) -> (usize, usize) {
+        scan_blocks(lines)
+            .into_iter()
+            .find_map(|block| match block {
+                Block::Function { name, start, end } if name == function => Some((start, end)),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn brace_delta_skips_quotes_expansions_and_comments() {
+        assert_eq!(brace_delta("prepare() {"), 1);
+        assert_eq!(brace_delta("}"), -1);
+        assert_eq!(brace_delta("prepare() { cd foo; }"), 0);
+        assert_eq!(brace_delta("  echo \"${pkgver%.*} }\" '{'"), 0);
+        assert_eq!(brace_delta("  make # {"), 0);
+        assert_eq!(brace_delta("  echo \\{"), 0);
+    }
+
+    #[test]
+    fn heredoc_delimiters_of_a_line() {
+        assert_eq!(
+            heredoc_delimiters("  cat <<EOF > f"),
+            vec![("EOF".to_string(), false)]
+        );
+        assert_eq!(
+            heredoc_delimiters("  cat <<-'END' >> f"),
+            vec![("END".to_string(), true)]
+        );
+        assert_eq!(
+            heredoc_delimiters("  cat << \"EOF\" | sed 's/a/b/'"),
+            vec![("EOF".to_string(), false)]
+        );
+        assert!(heredoc_delimiters("  read -r x <<< \"$y\"").is_empty());
+        assert!(heredoc_delimiters("  echo $((1 << 2))").is_empty());
+        assert!(heredoc_delimiters("  echo '<<EOF' # <<EOF").is_empty());
+    }
+
+    #[test]
+    fn scan_blocks_skips_heredoc_bodies() {
+        let content = "prepare() {\n  cat <<EOF > f\n}\nEOF\n  cd foo\n}\n\nbuild() {\n  make\n}";
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(function_lines(&lines, "prepare"), (0, 5));
+        assert_eq!(function_lines(&lines, "build"), (7, 9));
+    }
+
+    #[test]
+    fn scan_blocks_of_corpus() {
+        let content = corpus("heredoc");
+        let lines: Vec<&str> = content.lines().collect();
+        let (_, prepare_end) = function_lines(&lines, "prepare");
+        assert_eq!(lines[prepare_end], "}");
+        assert!(lines[prepare_end - 1].contains("sed -i"));
+        let (_, package_end) = function_lines(&lines, "package");
+        assert_eq!(package_end, lines.len() - 1);
+
+        let content = corpus("libfoo");
+        let lines: Vec<&str> = content.lines().collect();
+        let (start, end) = function_lines(&lines, "prepare");
+        assert_eq!(start, end);
+
+        let content = corpus("linux");
+        let lines: Vec<&str> = content.lines().collect();
+        let (start, end) = function_lines(&lines, "prepare");
+        assert!(lines[start].starts_with("prepare()"));
+        assert_eq!(lines[end], "}");
+        assert!(lines[end - 1].contains("Prepared $pkgbase"));
+    }
+
+    #[test]
+    fn parse_pkgbuild_of_corpus() {
+        let info = parse_pkgbuild(&corpus("nano")).unwrap();
+        assert_eq!(info.pkgbase, "nano");
+        assert_eq!(info.version(), "8.2-1");
+        assert_eq!(info.depends, vec!["glibc", "file", "ncurses", "sh"]);
+
+        let info = parse_pkgbuild(&corpus("linux")).unwrap();
+        assert_eq!(info.pkgbase, "linux");
+        assert_eq!(info.version(), "6.11.5.arch1-1");
+        assert_eq!(info.makedepends.len(), 9);
+        // pkgname=("$pkgbase") is defined after the package functions
+        assert_eq!(info.pkgnames, vec!["linux"]);
+
+        let info = parse_pkgbuild(&corpus("foo-bin")).unwrap();
+        assert_eq!(info.version(), "1.93.1-2");
+        assert_eq!(info.provides, vec!["foo"]);
+
+        for package in ["libfoo", "heredoc"] {
+            assert!(parse_pkgbuild(&corpus(package)).is_ok());
+        }
+    }
+
+    #[test]
+    fn parse_pkgbuild_of_split_packages() {
+        let content = r#"
+pkgbase=python-foo
+_name=foo
+pkgname=(
+  "python-$_name"   # the library
+  'python-${_name}-docs'
+  ${pkgbase}-tests
+)
+epoch=2
+pkgver="1.2.3"
+pkgrel='4'
+depends=("python>=3.12" 'glibc')
+depends+=(${_name}-data)
+makedepends=(python-build "${pkgbase}-setup" \
+  python-installer)
+provides=("lib${_name}.so=${pkgver%%.*}-64")
+
+package_python-foo() {
+  depends=(something-else)
+}
+"#;
+        let info = parse_pkgbuild(content).unwrap();
+        assert_eq!(info.pkgbase, "python-foo");
+        assert_eq!(
+            info.pkgnames,
+            vec!["python-foo", "python-${_name}-docs", "python-foo-tests"]
+        );
+        assert_eq!(info.epoch, "2");
+        assert_eq!(info.version(), "2:1.2.3-4");
+        assert_eq!(info.depends, vec!["python>=3.12", "glibc", "foo-data"]);
+        assert_eq!(
+            info.makedepends,
+            vec!["python-build", "python-foo-setup", "python-installer"]
+        );
+        // parameter expansions with modifiers are kept as written
+        assert_eq!(info.provides, vec!["libfoo.so=${pkgver%%.*}-64"]);
+    }
+
+    #[test]
+    fn parse_srcinfo_of_split_packages() {
+        let content = "pkgbase = python-foo
+\tepoch = 1
+\tpkgver = 1.2.3
+\tpkgrel = 4
+\tmakedepends = python-build
+\tdepends = glibc
+
+pkgname = python-foo
+\tdepends = glibc
+\tdepends = python
+
+pkgname = python-foo-docs
+";
+        let info = parse_srcinfo(content).unwrap();
+        assert_eq!(info.pkgnames, vec!["python-foo", "python-foo-docs"]);
+        assert_eq!(info.version(), "1:1.2.3-4");
+        assert_eq!(info.depends, vec!["glibc", "python"]);
+        assert_eq!(info.makedepends, vec!["python-build"]);
+    }
+
+    #[test]
+    fn parse_pkgbuild_without_version() {
+        assert!(parse_pkgbuild("pkgname=foo\npkgrel=1\n").is_err());
+        assert!(parse_srcinfo("pkgbase = foo\n\tpkgver = 1\n").is_err());
+    }
+}
@@ -0,0 +1,547 @@
+use colored::*;
+use glob::glob;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{read, remove_file};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::syncdb::{self, RepoPackage};
+use crate::vercmp::vercmp;
+use crate::{Config, LocalRepo, shell_quote};
+
+// struct to contain a package file in the directory of a local repository
+#[derive(Debug, Clone)]
+struct PackageFile {
+    path: String,
+    file_name: String,
+    name: String,
+    version: String,
+}
+
+fn parse_package_file(path: &str) -> Option<PackageFile> {
+    //! takes the path to a package file name-pkgver-pkgrel-arch.pkg.tar.zst and returns the name
+    //! and the version (epoch:pkgver-pkgrel) of the package
+    let file_name = Path::new(path).file_name()?.to_string_lossy().to_string();
+    let stem = file_name.strip_suffix(".pkg.tar.zst")?;
+    // the name can contain dashes --> split from the end
+    let mut parts = stem.rsplitn(4, '-');
+    let _arch = parts.next()?;
+    let pkgrel = parts.next()?;
+    let pkgver = parts.next()?;
+    let name = parts.next()?;
+
+    Some(PackageFile {
+        path: path.to_string(),
+        file_name: file_name.clone(),
+        name: name.to_string(),
+        version: format!("{}-{}", pkgver, pkgrel),
+    })
+}
+
+fn package_files(dir: &str) -> Vec<PackageFile> {
+    //! returns all package files in the directory
+    match glob(&format!("{}/*.pkg.tar.zst", dir)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| parse_package_file(&entry.to_string_lossy()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn declared_packages(configs: &Config) -> Vec<String> {
+    //! returns the names of all packages that are declared as patched packages or overlays
+    let mut declared: Vec<String> = configs
+        .patches
+        .iter()
+        .flat_map(|patches| patches.keys().cloned())
+        .collect();
+    declared.extend(configs.overlays.iter().cloned());
+    declared
+}
+
+fn is_declared(package: &RepoPackage, declared: &[String]) -> bool {
+    //! split packages are declared by their pkgbase
+    declared.contains(&package.name) || declared.contains(&package.base)
+}
+
+fn read_repository(repository: &LocalRepo) -> Result<Vec<RepoPackage>, String> {
+    //! returns the packages in the database of the local repository
+    syncdb::read_sync_db(&repository.path)
+        .map(|database| database.packages)
+        .map_err(|e| {
+            format!(
+                "Local repository {} couldn't be read: {}",
+                repository.path, e
+            )
+        })
+}
+
+fn repo_command(
+    tool: &str,
+    repository: &LocalRepo,
+    signing_key: &str,
+    arguments: &[String],
+) -> String {
+    //! returns the repo-add or repo-remove command for the repository
+    //! the database is signed again if a signing key is defined
+    //! the key, the database and the arguments are quoted for bash
+    let arguments: Vec<String> = arguments
+        .iter()
+        .map(|argument| shell_quote(argument))
+        .collect();
+    if signing_key.is_empty() {
+        format!(
+            "{} {} {}",
+            tool,
+            shell_quote(&repository.path),
+            arguments.join(" ")
+        )
+    } else {
+        format!(
+            "{} --sign --key {} {} {}",
+            tool,
+            shell_quote(signing_key),
+            shell_quote(&repository.path),
+            arguments.join(" ")
+        )
+    }
+}
+
+fn run(command: &str) -> Result<(), String> {
+    //! runs the bash command with its output on the terminal and returns an error if it failed
+    match Command::new("bash").arg("-c").arg(command).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed with {}", command, status)),
+        Err(e) => Err(format!("{} couldn't be started: {}", command, e)),
+    }
+}
+
+fn signature_valid(file_path: &str) -> bool {
+    //! returns true if the detached signature file_path.sig of the file is valid
+    Command::new("gpg")
+        .args([
+            "--batch",
+            "--verify",
+            &format!("{}.sig", file_path),
+            file_path,
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn remove_package_file(file_path: &str) {
+    //! removes the package file and its signature
+    match remove_file(file_path) {
+        Ok(()) => println!("Removed {}", file_path),
+        Err(e) => println!(
+            "{}",
+            format!("{} couldn't be removed: {}", file_path, e).red()
+        ),
+    }
+    let _ = remove_file(format!("{}.sig", file_path));
+}
+
+fn remove_packages(
+    repository: &LocalRepo,
+    names: &[String],
+    signing_key: &str,
+) -> Result<(), String> {
+    //! removes the packages from the database of the repository and deletes all of their files
+    run(&repo_command("repo-remove", repository, signing_key, names))?;
+    for file in package_files(&repository.dir()) {
+        if names.contains(&file.name) {
+            remove_package_file(&file.path);
+        }
+    }
+    Ok(())
+}
+
+pub fn list(configs: &Config) -> Result<(), String> {
+    //! prints the packages of all local repositories with their version
+    //! packages that are no longer declared as patched package or overlay are marked
+    let declared = declared_packages(configs);
+    for repository in &configs.local_repos {
+        let packages = read_repository(repository)?;
+        println!(
+            "{}",
+            format!("[{}] {} packages", repository.name, packages.len()).blue()
+        );
+
+        let width = packages
+            .iter()
+            .map(|package| package.name.len())
+            .max()
+            .unwrap_or(0);
+        for package in &packages {
+            let line = format!(
+                "  {:<width$}  {}",
+                package.name,
+                package.version,
+                width = width
+            );
+            if is_declared(package, &declared) {
+                println!("{}", line);
+            } else {
+                println!("{}", format!("{}  (not declared)", line).yellow());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn remove(configs: &Config, package_name: &str) -> Result<(), String> {
+    //! removes the package from all local repositories that contain it and deletes its files
+    //! a pkgbase removes all of its split packages
+    let mut found = false;
+    for repository in &configs.local_repos {
+        let names: Vec<String> = read_repository(repository)?
+            .iter()
+            .filter(|package| package.name == package_name || package.base == package_name)
+            .map(|package| package.name.clone())
+            .collect();
+        if names.is_empty() {
+            continue;
+        }
+        found = true;
+        remove_packages(repository, &names, &configs.signing_key)?;
+    }
+
+    if !found {
+        return Err(format!(
+            "Package {} not found in the local repositories",
+            package_name
+        ));
+    }
+    Ok(())
+}
+
+pub fn prune(configs: &Config, keep: usize, undeclared: bool) -> Result<(), String> {
+    //! removes old package files from the local repositories so that only the newest `keep`
+    //! versions of every package remain, the version in the database is always kept
+    //! packages that are no longer declared are reported and removed if undeclared is true
+    let declared = declared_packages(configs);
+    for repository in &configs.local_repos {
+        let packages = read_repository(repository)?;
+
+        // files of the packages in the database grouped by name
+        let mut files: HashMap<String, Vec<PackageFile>> = HashMap::new();
+        for file in package_files(&repository.dir()) {
+            if packages.iter().any(|package| package.name == file.name) {
+                files.entry(file.name.clone()).or_default().push(file);
+            }
+        }
+
+        for package in &packages {
+            let Some(versions) = files.get_mut(&package.name) else {
+                continue;
+            };
+            // newest version first
+            versions.sort_by(|a, b| vercmp(&b.version, &a.version));
+            for file in versions.iter().skip(keep) {
+                if file.file_name != package.filename {
+                    remove_package_file(&file.path);
+                }
+            }
+        }
+
+        let not_declared: Vec<String> = packages
+            .iter()
+            .filter(|package| !is_declared(package, &declared))
+            .map(|package| package.name.clone())
+            .collect();
+        if not_declared.is_empty() {
+            continue;
+        }
+        if undeclared {
+            println!(
+                "Removing packages that aren't declared from [{}]: {}",
+                repository.name,
+                not_declared.join(" ")
+            );
+            remove_packages(repository, &not_declared, &configs.signing_key)?;
+        } else {
+            println!(
+                "{}",
+                format!(
+                    "Packages in [{}] that aren't declared (remove with --undeclared): {}",
+                    repository.name,
+                    not_declared.join(" ")
+                )
+                .yellow()
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn verify(configs: &Config) -> Result<usize, String> {
+    //! checks that the file of every package in the local repositories exists and has the sha256
+    //! of the database, with a signing key the signatures of the packages and databases are
+    //! checked as well
+    //! package files that don't belong to any database are reported
+    //! returns the number of problems
+    let mut problems: Vec<String> = Vec::new();
+
+    let mut databases: Vec<(&LocalRepo, Vec<RepoPackage>)> = Vec::new();
+    for repository in &configs.local_repos {
+        databases.push((repository, read_repository(repository)?));
+    }
+
+    for (repository, packages) in &databases {
+        println!("{}", format!("Verifying [{}]", repository.name).blue());
+        if !configs.signing_key.is_empty() && !signature_valid(&repository.path) {
+            problems.push(format!(
+                "[{}]: signature of the database is missing or invalid",
+                repository.name
+            ));
+        }
+
+        for package in packages {
+            let file_path = format!("{}/{}", repository.dir(), package.filename);
+            let Ok(content) = read(&file_path) else {
+                problems.push(format!(
+                    "[{}] {}: file {} is missing",
+                    repository.name, package.name, package.filename
+                ));
+                continue;
+            };
+            if !package.sha256sum.is_empty()
+                && format!("{:x}", Sha256::digest(&content)) != package.sha256sum
+            {
+                problems.push(format!(
+                    "[{}] {}: sha256 of {} doesn't match the database",
+                    repository.name, package.name, package.filename
+                ));
+            }
+            if !configs.signing_key.is_empty() && !signature_valid(&file_path) {
+                problems.push(format!(
+                    "[{}] {}: signature of {} is missing or invalid",
+                    repository.name, package.name, package.filename
+                ));
+            }
+        }
+    }
+
+    // several repositories can share a directory
+    let mut dirs: Vec<String> = configs
+        .local_repos
+        .iter()
+        .map(|repository| repository.dir())
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    let all_packages: Vec<&RepoPackage> = databases
+        .iter()
+        .flat_map(|(_, packages)| packages.iter())
+        .collect();
+    for dir in dirs {
+        for file in package_files(&dir) {
+            if all_packages
+                .iter()
+                .any(|package| package.filename == file.file_name)
+            {
+                continue;
+            }
+            if all_packages.iter().any(|package| package.name == file.name) {
+                println!(
+                    "{}",
+                    format!("Old version {} (remove with repo prune)", file.path).yellow()
+                );
+            } else {
+                problems.push(format!(
+                    "{} doesn't belong to any local repository",
+                    file.path
+                ));
+            }
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem.red());
+    }
+    if problems.is_empty() {
+        println!("{}", "No problems found".green());
+    }
+    Ok(problems.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(path: &str) -> LocalRepo {
+        LocalRepo {
+            path: path.to_string(),
+            name: String::new(),
+            packages: String::new(),
+        }
+    }
+
+    fn temporary_dir(name: &str) -> String {
+        let dir = std::env::temp_dir()
+            .join(format!("nompac-test-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(database: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            name = "test"
+            build_dir = "/tmp"
+            patch_dir = "/tmp"
+            overlay_dir = "/tmp"
+            local_repo = "none"
+            local_repos = [{{ path = "{}", name = "custom" }}]
+            packages = []
+            patches = [{{ gcc = ["0001-fix.patch"] }}]
+            overlays = ["foo"]
+            pacconfig = "/etc/pacman.conf"
+            mirrorlist = "/etc/pacman.d/mirrorlist"
+            snapshot = "none"
+            configs = []
+            imports = []
+            "#,
+            database
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn package_file_names() {
+        // (file name, name, version)
+        let cases = [
+            ("foo-1.0-1-x86_64.pkg.tar.zst", "foo", "1.0-1"),
+            (
+                "gcc-libs-14.2.1-2-x86_64.pkg.tar.zst",
+                "gcc-libs",
+                "14.2.1-2",
+            ),
+            (
+                "python-foo-bar-1:2.0.r3.gabc-3-any.pkg.tar.zst",
+                "python-foo-bar",
+                "1:2.0.r3.gabc-3",
+            ),
+            (
+                "linux-6.11.5.arch1-1.1-x86_64.pkg.tar.zst",
+                "linux",
+                "6.11.5.arch1-1.1",
+            ),
+        ];
+        for (file_name, name, version) in cases {
+            let file = parse_package_file(&format!("/repo/{}", file_name)).unwrap();
+            assert_eq!(file.path, format!("/repo/{}", file_name));
+            assert_eq!(file.file_name, file_name);
+            assert_eq!(file.name, name);
+            assert_eq!(file.version, version);
+        }
+
+        assert!(parse_package_file("/repo/foo-1.0-1-x86_64.pkg.tar.zst.sig").is_none());
+        assert!(parse_package_file("/repo/custom.db.tar.zst").is_none());
+        assert!(parse_package_file("/repo/foo-x86_64.pkg.tar.zst").is_none());
+    }
+
+    #[test]
+    fn prune_keeps_newest_files_and_database_version() {
+        let dir = temporary_dir("repo-prune");
+        let database = format!("{}/custom.db.tar.zst", dir);
+        std::fs::copy(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/repo/custom.db.tar.zst"
+            ),
+            &database,
+        )
+        .unwrap();
+        // the database contains foo 1.0-1 and gcc-libs 14.2.1-2
+        let files = [
+            "foo-0.8-1-x86_64.pkg.tar.zst",
+            "foo-0.8-1-x86_64.pkg.tar.zst.sig",
+            "foo-0.9-1-x86_64.pkg.tar.zst",
+            "foo-1.0-1-x86_64.pkg.tar.zst",
+            "foo-1.1-1-x86_64.pkg.tar.zst",
+            "gcc-libs-14.2.1-1-x86_64.pkg.tar.zst",
+            "gcc-libs-14.2.1-2-x86_64.pkg.tar.zst",
+            "gcc-libs-14.2.1-10-x86_64.pkg.tar.zst",
+            // files of packages that aren't in the database are left alone
+            "bar-1.0-1-x86_64.pkg.tar.zst",
+            "bar-0.1-1-x86_64.pkg.tar.zst",
+        ];
+        for file in files {
+            std::fs::write(format!("{}/{}", dir, file), "").unwrap();
+        }
+        let remaining = || {
+            let mut names: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|name| name.contains(".pkg.tar.zst"))
+                .collect();
+            names.sort();
+            names
+        };
+
+        let configs = config(&database);
+        prune(&configs, 2, false).unwrap();
+        assert_eq!(
+            remaining(),
+            vec![
+                "bar-0.1-1-x86_64.pkg.tar.zst",
+                "bar-1.0-1-x86_64.pkg.tar.zst",
+                "foo-1.0-1-x86_64.pkg.tar.zst",
+                "foo-1.1-1-x86_64.pkg.tar.zst",
+                "gcc-libs-14.2.1-10-x86_64.pkg.tar.zst",
+                "gcc-libs-14.2.1-2-x86_64.pkg.tar.zst",
+            ]
+        );
+
+        // the version in the database is kept even if it isn't among the newest
+        std::fs::write(format!("{}/foo-1.2-1-x86_64.pkg.tar.zst", dir), "").unwrap();
+        prune(&configs, 1, false).unwrap();
+        assert_eq!(
+            remaining(),
+            vec![
+                "bar-0.1-1-x86_64.pkg.tar.zst",
+                "bar-1.0-1-x86_64.pkg.tar.zst",
+                "foo-1.0-1-x86_64.pkg.tar.zst",
+                "foo-1.2-1-x86_64.pkg.tar.zst",
+                "gcc-libs-14.2.1-10-x86_64.pkg.tar.zst",
+                "gcc-libs-14.2.1-2-x86_64.pkg.tar.zst",
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repo_command_quotes_its_arguments() {
+        let repository = repository("/home/me/my repo/nompac.db.tar.zst");
+        assert_eq!(
+            repo_command("repo-remove", &repository, "", &["foo".to_string()]),
+            "repo-remove '/home/me/my repo/nompac.db.tar.zst' 'foo'"
+        );
+
+        // bash receives every value as one word without expanding it
+        let command = repo_command(
+            "printf '%s\\n'",
+            &repository,
+            "Me <me@example.org>; rm -rf $HOME",
+            &["it's".to_string(), "$(id)".to_string()],
+        );
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(&command)
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "--sign\n--key\nMe <me@example.org>; rm -rf $HOME\n/home/me/my repo/nompac.db.tar.zst\nit's\n$(id)\n"
+        );
+    }
+}
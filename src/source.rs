@@ -0,0 +1,558 @@
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, copy, create_dir_all, read_dir, remove_dir_all, rename};
+use std::io::BufReader;
+use std::path::Path;
+use std::process::Command;
+use tar::Archive;
+use walkdir::WalkDir;
+
+use crate::cache::Cache;
+use crate::pkgbuild::{self, PkgbuildInfo};
+use crate::{Config, get_current_version_from_repo, resolve_home, shell_quote};
+
+// where the build files of a patched package come from
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    // archive of the tag in the Arch GitLab, the version is the one in the sync databases
+    #[default]
+    Gitlab,
+    // git repository at a tag, branch or commit
+    Git {
+        url: String,
+        rev: String,
+    },
+    // git repository of the package in the AUR
+    Aur {
+        #[serde(default = "default_aur_rev")]
+        rev: String,
+    },
+    // directory containing the PKGBUILD
+    Local {
+        path: String,
+    },
+}
+
+fn default_aur_rev() -> String {
+    "HEAD".to_string()
+}
+
+// backend that provides the version and the build files of a package
+pub trait SourceBackend {
+    /// returns the version (epoch:pkgver-pkgrel) that the backend provides
+    fn latest_version(&self, package: &str) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// returns the version without downloading anything into the cache, None if the version can't
+    /// be known without a download (used by the dry run)
+    fn known_version(&self, package: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        self.latest_version(package).map(Some)
+    }
+
+    /// puts the build files of the version into the directory
+    fn fetch(
+        &self,
+        package: &str,
+        version: &str,
+        dest_dir: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+pub fn backend<'a>(configs: &'a Config, source: &SourceConfig) -> Box<dyn SourceBackend + 'a> {
+    //! returns the backend for the source of a package
+    let cache = Cache::new(&configs.build_dir, configs.cache_size);
+    match source {
+        SourceConfig::Gitlab => Box::new(ArchGitlab { configs, cache }),
+        SourceConfig::Git { url, rev } => Box::new(GitRepo {
+            url: url.clone(),
+            rev: rev.clone(),
+            cache,
+        }),
+        SourceConfig::Aur { rev } => Box::new(Aur {
+            rev: rev.clone(),
+            cache,
+        }),
+        SourceConfig::Local { path } => Box::new(LocalDir {
+            path: resolve_home(path.clone()),
+        }),
+    }
+}
+
+fn extract_tgz(filename: &str, output_path: &str) -> Result<(), std::io::Error> {
+    //! takes filename to be extracted and the output path
+    //! extract a tar.gz file
+    let file = File::open(filename)?;
+    let reader = BufReader::new(GzDecoder::new(file));
+
+    let mut archive = Archive::new(reader);
+
+    archive.unpack(output_path)?;
+
+    Ok(())
+}
+
+fn run_git(arguments: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+    //! runs git with the arguments and returns its output
+    let output = Command::new("git").args(arguments).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            arguments.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// tarball of a tag in the packaging repositories of the Arch GitLab
+pub struct ArchGitlab<'a> {
+    configs: &'a Config,
+    cache: Cache,
+}
+
+impl SourceBackend for ArchGitlab<'_> {
+    fn latest_version(&self, package: &str) -> Result<String, Box<dyn std::error::Error>> {
+        get_current_version_from_repo(self.configs, package)
+    }
+
+    fn fetch(
+        &self,
+        package: &str,
+        version: &str,
+        dest_dir: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // the tags in the arch repository replace the colon of the epoch with a dash
+        let tag = version.replace(':', "-");
+        let url = format!(
+            "https://gitlab.archlinux.org/archlinux/packaging/packages/{}/-/archive/{}/{}-{}.tar.gz",
+            package, tag, package, tag
+        );
+        let tarball = self.cache.fetch(&url)?;
+
+        // the archive contains one directory package-tag that becomes the destination
+        let dest_dir = dest_dir.trim_end_matches('/');
+        let extract_dir = format!("{}.extract", dest_dir);
+        let _ = remove_dir_all(&extract_dir);
+        let _ = remove_dir_all(dest_dir);
+        extract_tgz(&tarball, &extract_dir)?;
+        let Some(root) = read_dir(&extract_dir)?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().is_dir())
+        else {
+            return Err(format!("{} contains no directory", url).into());
+        };
+        rename(root.path(), dest_dir)?;
+        remove_dir_all(&extract_dir)?;
+
+        Ok(())
+    }
+}
+
+// git repository with the PKGBUILD at its root, the repository is mirrored in the cache
+pub struct GitRepo {
+    url: String,
+    rev: String,
+    cache: Cache,
+}
+
+impl GitRepo {
+    fn update_mirror(&self) -> Result<String, Box<dyn std::error::Error>> {
+        //! clones the repository into the cache or fetches the changes and returns the directory
+        let mirror = self.cache.git_dir(&self.url);
+        if Path::new(&mirror).is_dir() {
+            run_git(&["-C", &mirror, "remote", "update", "--prune"])?;
+        } else {
+            run_git(&["clone", "--quiet", "--mirror", &self.url, &mirror])?;
+        }
+        Ok(mirror)
+    }
+
+    fn mirror_is_current(&self, mirror: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        //! returns true if the revision in the mirror is the same as in the remote repository
+        //! the remote is only queried with git ls-remote, so nothing is downloaded
+        let Ok(local) = run_git(&[
+            "-C",
+            mirror,
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", self.rev),
+        ]) else {
+            return Ok(false);
+        };
+        let local = local.trim();
+        let remote = run_git(&[
+            "ls-remote",
+            &self.url,
+            &self.rev,
+            &format!("{}^{{}}", self.rev),
+        ])?;
+        let refs: Vec<(&str, &str)> = remote
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .collect();
+        if refs.is_empty() {
+            // the revision isn't a branch or tag but a commit, which can't change
+            return Ok(local.starts_with(&self.rev));
+        }
+        // annotated tags are listed twice, the commit is the one with ^{}
+        let remote_commit = refs
+            .iter()
+            .find(|(_, name)| name.ends_with("^{}"))
+            .or(refs.first())
+            .map(|(commit, _)| *commit);
+        Ok(remote_commit == Some(local))
+    }
+
+    fn package_info(&self, mirror: &str) -> Result<PkgbuildInfo, Box<dyn std::error::Error>> {
+        //! reads the .SRCINFO of the revision, or the PKGBUILD if there is no .SRCINFO
+        match run_git(&["-C", mirror, "show", &format!("{}:.SRCINFO", self.rev)]) {
+            Ok(srcinfo) => pkgbuild::parse_srcinfo(&srcinfo),
+            Err(_) => pkgbuild::parse_pkgbuild(&run_git(&[
+                "-C",
+                mirror,
+                "show",
+                &format!("{}:PKGBUILD", self.rev),
+            ])?),
+        }
+    }
+}
+
+impl SourceBackend for GitRepo {
+    fn latest_version(&self, _package: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let mirror = self.update_mirror()?;
+        Ok(self.package_info(&mirror)?.version())
+    }
+
+    fn known_version(&self, _package: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mirror = self.cache.git_dir(&self.url);
+        if !Path::new(&mirror).is_dir() || !self.mirror_is_current(&mirror)? {
+            return Ok(None);
+        }
+        Ok(Some(self.package_info(&mirror)?.version()))
+    }
+
+    fn fetch(
+        &self,
+        _package: &str,
+        _version: &str,
+        dest_dir: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mirror = self.update_mirror()?;
+        let _ = remove_dir_all(dest_dir);
+        create_dir_all(dest_dir)?;
+
+        // the output of git and tar is only shown in the error
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "set -o pipefail; git -C {} archive --format=tar {} | tar -x -C {}",
+                shell_quote(&mirror),
+                shell_quote(&self.rev),
+                shell_quote(dest_dir)
+            ))
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "revision {} of {} couldn't be exported: {}",
+                self.rev,
+                self.url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+// git repository of the package in the AUR
+pub struct Aur {
+    rev: String,
+    cache: Cache,
+}
+
+impl Aur {
+    fn repository(&self, package: &str) -> GitRepo {
+        GitRepo {
+            url: format!("https://aur.archlinux.org/{}.git", package),
+            rev: self.rev.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl SourceBackend for Aur {
+    fn latest_version(&self, package: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.repository(package).latest_version(package)
+    }
+
+    fn known_version(&self, package: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        self.repository(package).known_version(package)
+    }
+
+    fn fetch(
+        &self,
+        package: &str,
+        version: &str,
+        dest_dir: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.repository(package).fetch(package, version, dest_dir)
+    }
+}
+
+// local directory with the build files, e.g. a checkout that is maintained by hand
+pub struct LocalDir {
+    path: String,
+}
+
+impl SourceBackend for LocalDir {
+    fn latest_version(&self, _package: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(pkgbuild::read_package_info(&self.path)?.version())
+    }
+
+    fn fetch(
+        &self,
+        _package: &str,
+        _version: &str,
+        dest_dir: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = remove_dir_all(dest_dir);
+        for entry in WalkDir::new(&self.path)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .filter_map(|entry| entry.ok())
+        {
+            let relative = entry.path().strip_prefix(&self.path)?;
+            let target = Path::new(dest_dir).join(relative);
+            if entry.path().is_dir() {
+                create_dir_all(&target)?;
+            } else {
+                copy(entry.path(), &target)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::PackagePatches;
+    use std::collections::HashMap;
+    use std::fs::{read_to_string, write};
+
+    const PKGBUILD: &str = "pkgname=foo\npkgver=1.0\npkgrel=1\narch=('any')\nsource=()\n";
+
+    fn temporary_dir(name: &str) -> String {
+        let dir = std::env::temp_dir()
+            .join(format!("nompac-test-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string();
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn git(dir: &str, arguments: &[&str]) {
+        let mut all_arguments = vec![
+            "-C",
+            dir,
+            "-c",
+            "user.name=nompac",
+            "-c",
+            "user.email=nompac@localhost",
+        ];
+        all_arguments.extend(arguments);
+        run_git(&all_arguments).unwrap();
+    }
+
+    #[test]
+    fn local_dir_backend() {
+        let dir = temporary_dir("local-source");
+        let dest_dir = temporary_dir("local-dest");
+        create_dir_all(format!("{}/.git", dir)).unwrap();
+        create_dir_all(format!("{}/keys/pgp", dir)).unwrap();
+        write(format!("{}/.git/HEAD", dir), "ref: refs/heads/main\n").unwrap();
+        write(format!("{}/PKGBUILD", dir), PKGBUILD).unwrap();
+        write(format!("{}/keys/pgp/key.asc", dir), "key").unwrap();
+
+        let backend = LocalDir { path: dir.clone() };
+        assert_eq!(backend.latest_version("foo").unwrap(), "1.0-1");
+        backend.fetch("foo", "1.0-1", &dest_dir).unwrap();
+        assert_eq!(
+            read_to_string(format!("{}/PKGBUILD", dest_dir)).unwrap(),
+            PKGBUILD
+        );
+        assert!(Path::new(&format!("{}/keys/pgp/key.asc", dest_dir)).is_file());
+        // the repository of a checkout isn't copied
+        assert!(!Path::new(&format!("{}/.git", dest_dir)).exists());
+
+        // a .SRCINFO is preferred over the PKGBUILD
+        write(
+            format!("{}/.SRCINFO", dir),
+            "pkgbase = foo\n\tpkgver = 1.1\n\tpkgrel = 2\n\tepoch = 1\n\npkgname = foo\n",
+        )
+        .unwrap();
+        assert_eq!(backend.latest_version("foo").unwrap(), "1:1.1-2");
+
+        let _ = remove_dir_all(&dir);
+        let _ = remove_dir_all(&dest_dir);
+    }
+
+    #[test]
+    fn git_backend_known_version_without_download() {
+        let upstream = temporary_dir("git-known-upstream");
+        let build_dir = temporary_dir("git-known-build");
+
+        git(&upstream, &["init", "--quiet"]);
+        write(format!("{}/PKGBUILD", upstream), PKGBUILD).unwrap();
+        git(&upstream, &["add", "PKGBUILD"]);
+        git(&upstream, &["commit", "--quiet", "-m", "1.0-1"]);
+        git(&upstream, &["tag", "-a", "-m", "1.0-1", "1.0-1"]);
+        let commit = run_git(&["-C", &upstream, "rev-parse", "HEAD"]).unwrap();
+
+        let backend = |rev: &str| GitRepo {
+            url: upstream.clone(),
+            rev: rev.to_string(),
+            cache: Cache::new(&build_dir, 0),
+        };
+        // without a mirror the version is unknown and no mirror is created
+        assert_eq!(backend("HEAD").known_version("foo").unwrap(), None);
+        assert!(!Path::new(&backend("HEAD").cache.git_dir(&upstream)).exists());
+
+        assert_eq!(backend("HEAD").latest_version("foo").unwrap(), "1.0-1");
+        for rev in ["HEAD", "1.0-1", commit.trim(), &commit[..12]] {
+            assert_eq!(
+                backend(rev).known_version("foo").unwrap(),
+                Some("1.0-1".to_string()),
+                "{}",
+                rev
+            );
+        }
+
+        // the mirror is outdated after a new commit upstream
+        write(
+            format!("{}/PKGBUILD", upstream),
+            PKGBUILD.replace("pkgver=1.0", "pkgver=1.1"),
+        )
+        .unwrap();
+        git(&upstream, &["commit", "--quiet", "-am", "1.1-1"]);
+        assert_eq!(backend("HEAD").known_version("foo").unwrap(), None);
+        assert_eq!(
+            backend("1.0-1").known_version("foo").unwrap(),
+            Some("1.0-1".to_string())
+        );
+
+        let _ = remove_dir_all(&upstream);
+        let _ = remove_dir_all(&build_dir);
+    }
+
+    #[test]
+    fn local_dir_backend_without_pkgbuild() {
+        let dir = temporary_dir("local-empty");
+        let backend = LocalDir { path: dir.clone() };
+        assert!(backend.latest_version("foo").is_err());
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn git_backend_with_local_repository() {
+        let upstream = temporary_dir("git-upstream");
+        let build_dir = temporary_dir("git-build");
+        // paths with spaces and quotes are passed to the export as they are
+        let dest_dir = format!("{}/foo's build files", build_dir);
+
+        git(&upstream, &["init", "--quiet"]);
+        write(format!("{}/PKGBUILD", upstream), PKGBUILD).unwrap();
+        git(&upstream, &["add", "PKGBUILD"]);
+        git(&upstream, &["commit", "--quiet", "-m", "1.0-1"]);
+        git(&upstream, &["tag", "1.0-1"]);
+
+        let backend = |rev: &str| GitRepo {
+            url: upstream.clone(),
+            rev: rev.to_string(),
+            cache: Cache::new(&build_dir, 0),
+        };
+        assert_eq!(backend("HEAD").latest_version("foo").unwrap(), "1.0-1");
+
+        // a new commit is fetched into the mirror, the tag still points to the old version
+        write(
+            format!("{}/PKGBUILD", upstream),
+            PKGBUILD.replace("pkgver=1.0", "pkgver=1.1"),
+        )
+        .unwrap();
+        write(format!("{}/fix.patch", upstream), "patch").unwrap();
+        git(&upstream, &["add", "PKGBUILD", "fix.patch"]);
+        git(&upstream, &["commit", "--quiet", "-m", "1.1-1"]);
+        assert_eq!(backend("HEAD").latest_version("foo").unwrap(), "1.1-1");
+        assert_eq!(backend("1.0-1").latest_version("foo").unwrap(), "1.0-1");
+
+        backend("HEAD").fetch("foo", "1.1-1", &dest_dir).unwrap();
+        assert!(
+            read_to_string(format!("{}/PKGBUILD", dest_dir))
+                .unwrap()
+                .contains("pkgver=1.1")
+        );
+        assert!(Path::new(&format!("{}/fix.patch", dest_dir)).is_file());
+        assert!(!Path::new(&format!("{}/.git", dest_dir)).exists());
+
+        // the files of an older revision replace the ones of the previous fetch
+        backend("1.0-1").fetch("foo", "1.0-1", &dest_dir).unwrap();
+        assert!(!Path::new(&format!("{}/fix.patch", dest_dir)).exists());
+
+        assert!(backend("missing").latest_version("foo").is_err());
+        assert_eq!(backend("missing").known_version("foo").unwrap(), None);
+        let error = backend("missing")
+            .fetch("foo", "1.0-1", &dest_dir)
+            .unwrap_err();
+        assert!(error.to_string().contains("not a valid object name"));
+
+        let _ = remove_dir_all(&upstream);
+        let _ = remove_dir_all(&build_dir);
+    }
+
+    #[test]
+    fn package_patches_from_toml() {
+        let patches: HashMap<String, PackagePatches> = toml::from_str(
+            r#"
+            plain = ["a.patch", { file = "b.patch", strip = 0 }]
+            from_git = { source = { type = "git", url = "https://example.org/foo.git", rev = "v1" }, patches = ["c.patch"] }
+            from_aur = { source = { type = "aur" } }
+            [from_local]
+            source = { type = "local", path = "~/pkgbuilds/foo" }
+            patches = ["d.patch"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(patches["plain"].source(), SourceConfig::Gitlab);
+        assert_eq!(patches["plain"].patches().len(), 2);
+        assert!(matches!(patches["plain"], PackagePatches::List(_)));
+
+        assert_eq!(
+            patches["from_git"].source(),
+            SourceConfig::Git {
+                url: "https://example.org/foo.git".to_string(),
+                rev: "v1".to_string()
+            }
+        );
+        assert_eq!(patches["from_git"].patches().len(), 1);
+        assert!(matches!(patches["from_git"], PackagePatches::Table { .. }));
+
+        assert_eq!(
+            patches["from_aur"].source(),
+            SourceConfig::Aur {
+                rev: "HEAD".to_string()
+            }
+        );
+        assert!(patches["from_aur"].patches().is_empty());
+
+        assert_eq!(
+            patches["from_local"].source(),
+            SourceConfig::Local {
+                path: "~/pkgbuilds/foo".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::fs::read;
+use std::io::Read;
+use std::path::Path;
+use tar::Archive;
+
+use crate::localdb::{first_value, parse_sections};
+
+// default location of the pacman sync databases
+pub const SYNC_DB_PATH: &str = "/var/lib/pacman/sync";
+
+// struct to contain the entries of a package in a sync database
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone)]
+pub struct RepoPackage {
+    pub repository: String,
+    pub name: String,
+    pub version: String,
+    pub base: String,
+    pub groups: Vec<String>,
+    pub depends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub provides: Vec<String>,
+    pub replaces: Vec<String>,
+    // file name of the package and sha256 of the file
+    pub filename: String,
+    pub sha256sum: String,
+}
+
+// struct to contain all packages of one repository
+#[derive(Debug, Default, Clone)]
+pub struct SyncDb {
+    pub repository: String,
+    pub packages: Vec<RepoPackage>,
+}
+
+fn decompress(content: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    //! takes the content of a database file and returns the uncompressed tar archive
+    //! the compression is detected by the magic bytes of the file
+    let mut uncompressed: Vec<u8> = Vec::new();
+
+    if content.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(content.as_slice()).read_to_end(&mut uncompressed)?;
+    } else if content.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let mut content_slice = content.as_slice();
+        ruzstd::StreamingDecoder::new(&mut content_slice)
+            .map_err(|e| format!("Couldn't decode zstd archive: {}", e))?
+            .read_to_end(&mut uncompressed)?;
+    } else {
+        // uncompressed tar archive
+        uncompressed = content;
+    }
+
+    Ok(uncompressed)
+}
+
+pub fn repository_name(file_path: &str) -> String {
+    //! takes the path to a database file and returns the repository name
+    //! e.g. /var/lib/pacman/sync/extra.db or /repo/nomispaz.db.tar.zst
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    match file_name.split_once(".db") {
+        Some((name, _)) => name.to_string(),
+        None => file_name,
+    }
+}
+
+pub fn configured_repositories(pacman_conf: &str) -> Vec<String> {
+    //! takes the content of a pacman.conf and returns the repositories in the order of their
+    //! sections, the order in which pacman searches them
+    pacman_conf
+        .lines()
+        .map(|line| line.trim())
+        .filter_map(|line| line.strip_prefix('[')?.strip_suffix(']'))
+        .map(|section| section.trim().to_string())
+        .filter(|section| section != "options")
+        .collect()
+}
+
+pub fn read_sync_db(file_path: &str) -> Result<SyncDb, Box<dyn std::error::Error>> {
+    //! takes the path to a sync database (.db or .db.tar.zst) and returns all packages
+    parse_sync_db(&repository_name(file_path), read(file_path)?)
+}
+
+pub fn parse_sync_db(
+    repository: &str,
+    content: Vec<u8>,
+) -> Result<SyncDb, Box<dyn std::error::Error>> {
+    //! takes the name of the repository and the content of its database and returns all packages
+    let repository = repository.to_string();
+    let archive_content = decompress(content)?;
+    let mut archive = Archive::new(archive_content.as_slice());
+
+    // older databases split the entries of one package into desc and depends files
+    // --> collect the sections per package directory first
+    let mut entries: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.to_string_lossy().to_string();
+        let Some((directory, file_name)) = entry_path.rsplit_once("/") else {
+            continue;
+        };
+        if file_name != "desc" && file_name != "depends" {
+            continue;
+        }
+
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        entries
+            .entry(directory.to_string())
+            .or_default()
+            .extend(parse_sections(&content));
+    }
+
+    let mut packages: Vec<RepoPackage> = entries
+        .values()
+        .map(|sections| {
+            let mut package = RepoPackage {
+                repository: repository.clone(),
+                name: first_value(sections, "NAME"),
+                version: first_value(sections, "VERSION"),
+                base: first_value(sections, "BASE"),
+                groups: sections.get("GROUPS").cloned().unwrap_or_default(),
+                depends: sections.get("DEPENDS").cloned().unwrap_or_default(),
+                makedepends: sections.get("MAKEDEPENDS").cloned().unwrap_or_default(),
+                provides: sections.get("PROVIDES").cloned().unwrap_or_default(),
+                replaces: sections.get("REPLACES").cloned().unwrap_or_default(),
+                filename: first_value(sections, "FILENAME"),
+                sha256sum: first_value(sections, "SHA256SUM"),
+            };
+            if package.base.is_empty() {
+                package.base = package.name.clone();
+            }
+            package
+        })
+        .collect();
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(SyncDb {
+        repository,
+        packages,
+    })
+}
+
+pub fn download_sync_db(
+    server: &str,
+    repository: &str,
+) -> Result<SyncDb, Box<dyn std::error::Error>> {
+    //! takes a server of pacman.conf (with $repo and $arch) and the repository and returns the
+    //! packages of the database on the server
+    let url = format!(
+        "{}/{}.db",
+        server
+            .replace("$repo", repository)
+            .replace("$arch", std::env::consts::ARCH),
+        repository
+    );
+    let response = reqwest::blocking::get(&url)?;
+    if !response.status().is_success() {
+        return Err(format!("download of {} failed with {}", url, response.status()).into());
+    }
+    parse_sync_db(repository, response.bytes()?.to_vec())
+}
+
+pub fn read_sync_dbs(db_path: &str, repositories: &[String]) -> Vec<SyncDb> {
+    //! takes the path to the sync directory (e.g. /var/lib/pacman/sync) and the repositories of
+    //! pacman.conf and returns their databases in the same order
+    //! databases of other repositories are ignored, a database that can't be read is skipped
+    let mut databases: Vec<SyncDb> = Vec::new();
+
+    for repository in repositories {
+        let file_path = format!("{}/{}.db", db_path, repository);
+        match read_sync_db(&file_path) {
+            Ok(database) => databases.push(database),
+            Err(e) => eprintln!("Sync database {} couldn't be read: {}", file_path, e),
+        }
+    }
+
+    databases
+}
+
+pub fn find_repo_package<'a>(
+    databases: &'a [SyncDb],
+    package_name: &str,
+    excluded_repositories: &[String],
+) -> Option<&'a RepoPackage> {
+    //! takes a list of repositories and returns the first package with the given name
+    //! if no package has the name, the first package with the name as pkgbase is returned
+    //! packages in the excluded repositories (e.g. the local repository) are skipped
+    let packages = || {
+        databases
+            .iter()
+            .filter(|database| !excluded_repositories.contains(&database.repository))
+            .flat_map(|database| database.packages.iter())
+    };
+
+    packages()
+        .find(|package| package.name == package_name)
+        .or_else(|| packages().find(|package| package.base == package_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    fn fixture(path: &str) -> String {
+        format!("{}/{}", FIXTURES, path)
+    }
+
+    #[test]
+    fn repository_name_of_db_files() {
+        assert_eq!(repository_name("/var/lib/pacman/sync/extra.db"), "extra");
+        assert_eq!(repository_name("/repo/foo.db.tar.zst"), "foo");
+        assert_eq!(repository_name("foo.db.tar.zst"), "foo");
+        assert_eq!(repository_name("/repo/nodb"), "nodb");
+    }
+
+    #[test]
+    fn read_gzip_database() {
+        let database = read_sync_db(&fixture("sync/extra.db")).unwrap();
+        assert_eq!(database.repository, "extra");
+        let names: Vec<&str> = database
+            .packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["gnome-shell", "python"]);
+
+        let python = &database.packages[1];
+        assert_eq!(python.repository, "extra");
+        assert_eq!(python.version, "3.12.7-1");
+        assert_eq!(python.base, "python");
+        assert_eq!(python.depends, vec!["expat"]);
+        assert_eq!(python.makedepends, vec!["tk"]);
+        assert_eq!(python.provides, vec!["python3=3.12.7"]);
+        assert_eq!(python.replaces, vec!["python3-legacy"]);
+        assert_eq!(python.filename, "python-3.12.7-1-x86_64.pkg.tar.zst");
+        assert_eq!(database.packages[0].groups, vec!["gnome"]);
+    }
+
+    #[test]
+    fn read_zstd_database() {
+        let database = read_sync_db(&fixture("repo/custom.db.tar.zst")).unwrap();
+        assert_eq!(database.repository, "custom");
+        assert_eq!(database.packages.len(), 2);
+        let gcc_libs = &database.packages[1];
+        assert_eq!(gcc_libs.name, "gcc-libs");
+        assert_eq!(gcc_libs.base, "gcc");
+        assert_eq!(gcc_libs.version, "14.2.1-2");
+    }
+
+    #[test]
+    fn read_database_with_split_depends_file() {
+        let database = read_sync_db(&fixture("old/old.db")).unwrap();
+        assert_eq!(database.packages.len(), 1);
+        let bar = &database.packages[0];
+        assert_eq!(bar.name, "bar");
+        assert_eq!(bar.version, "2.0-1");
+        // the entries of the depends file are merged into the package
+        assert_eq!(bar.depends, vec!["glibc", "zlib"]);
+        assert_eq!(bar.provides, vec!["libbar.so=1-64"]);
+        // without %BASE% the name is used
+        assert_eq!(bar.base, "bar");
+    }
+
+    fn repositories(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn repositories_of_pacman_conf() {
+        let pacman_conf = "[options]\nArchitecture = auto\n\n#[testing]\n[core-testing]\nInclude = /etc/pacman.d/mirrorlist\n\n[core]\nInclude = /etc/pacman.d/mirrorlist\n [extra] \nServer = file:///repo\n";
+        assert_eq!(
+            configured_repositories(pacman_conf),
+            vec!["core-testing", "core", "extra"]
+        );
+    }
+
+    #[test]
+    fn read_databases_in_pacman_conf_order() {
+        let databases = read_sync_dbs(&fixture("sync"), &repositories(&["extra", "core"]));
+        let names: Vec<&str> = databases
+            .iter()
+            .map(|database| database.repository.as_str())
+            .collect();
+        assert_eq!(names, vec!["extra", "core"]);
+        assert_eq!(databases[1].packages[0].sha256sum, "0123abcd");
+
+        // databases of repositories that aren't configured are ignored
+        let databases = read_sync_dbs(&fixture("sync"), &repositories(&["core"]));
+        assert_eq!(databases.len(), 1);
+    }
+
+    #[test]
+    fn read_databases_skips_unreadable_ones() {
+        let databases = read_sync_dbs(
+            &fixture("sync"),
+            &repositories(&["missing", "core", "extra"]),
+        );
+        assert_eq!(databases.len(), 2);
+        assert_eq!(databases[0].repository, "core");
+    }
+
+    #[test]
+    fn read_missing_database() {
+        assert!(read_sync_db(&fixture("sync/missing.db")).is_err());
+    }
+
+    #[test]
+    fn find_repo_package_by_name_and_pkgbase() {
+        let mut databases = vec![read_sync_db(&fixture("repo/custom.db.tar.zst")).unwrap()];
+        databases.extend(read_sync_dbs(
+            &fixture("sync"),
+            &repositories(&["core", "extra"]),
+        ));
+
+        let python = find_repo_package(&databases, "python", &[]).unwrap();
+        assert_eq!(python.repository, "extra");
+
+        // there is no package gcc --> the first package with the pkgbase gcc
+        let gcc = find_repo_package(&databases, "gcc", &[]).unwrap();
+        assert_eq!(gcc.name, "gcc-libs");
+        assert_eq!(gcc.repository, "custom");
+
+        assert!(find_repo_package(&databases, "firefox", &[]).is_none());
+    }
+
+    #[test]
+    fn find_repo_package_skips_excluded_repositories() {
+        let mut databases = vec![read_sync_db(&fixture("repo/custom.db.tar.zst")).unwrap()];
+        databases.extend(read_sync_dbs(
+            &fixture("sync"),
+            &repositories(&["core", "extra"]),
+        ));
+        let excluded = vec!["custom".to_string()];
+
+        let gcc_libs = find_repo_package(&databases, "gcc-libs", &excluded).unwrap();
+        assert_eq!(gcc_libs.repository, "core");
+        assert_eq!(gcc_libs.version, "14.2.1-1");
+        let gcc = find_repo_package(&databases, "gcc", &excluded).unwrap();
+        assert_eq!(gcc.repository, "core");
+        assert!(find_repo_package(&databases, "foo", &excluded).is_none());
+    }
+}
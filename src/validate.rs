@@ -0,0 +1,284 @@
+use std::path::Path;
+
+use crate::Config;
+use crate::syncdb::{RepoPackage, SyncDb};
+
+// declared name that doesn't exist
+#[derive(Debug, Clone)]
+pub struct UnknownName {
+    // "package", "group" or "overlay"
+    pub kind: String,
+    pub name: String,
+    // config file that declares the name
+    pub file: String,
+    pub suggestions: Vec<String>,
+}
+
+impl UnknownName {
+    pub fn message(&self) -> String {
+        let mut message = format!("unknown {} {} in {}", self.kind, self.name, self.file);
+        if !self.suggestions.is_empty() {
+            message.push_str(&format!(" --> {}", self.suggestions.join(", ")));
+        }
+        message
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    //! returns the levenshtein distance of the two strings
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, char_a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, char_b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(char_a != *char_b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn close_matches<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Vec<String> {
+    //! returns up to three candidates that differ from the name by a few characters
+    let max_distance = (name.chars().count() / 4).clamp(1, 3);
+    let mut matches: Vec<(usize, &String)> = candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+    matches
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| format!("did you mean {}?", candidate))
+        .collect()
+}
+
+fn package_suggestions(name: &str, packages: &[&RepoPackage]) -> Vec<String> {
+    //! returns the successors of a renamed package, the providers of the name and close matches
+    let mut suggestions: Vec<String> = Vec::new();
+
+    let strip = |dependency: &String| {
+        dependency
+            .split(['<', '>', '='])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    for package in packages {
+        if package
+            .replaces
+            .iter()
+            .any(|replaced| strip(replaced) == name)
+        {
+            suggestions.push(format!("replaced by {}", package.name));
+        }
+    }
+    for package in packages {
+        if package
+            .provides
+            .iter()
+            .any(|provided| strip(provided) == name)
+        {
+            suggestions.push(format!("provided by {}", package.name));
+        }
+    }
+    suggestions.extend(close_matches(
+        name,
+        packages.iter().map(|package| &package.name),
+    ));
+
+    suggestions
+}
+
+fn providers(name: &str, packages: &[&RepoPackage]) -> Vec<String> {
+    //! returns the names of the packages that provide the name
+    packages
+        .iter()
+        .filter(|package| {
+            package
+                .provides
+                .iter()
+                .any(|provided| provided.split(['<', '>', '=']).next().unwrap_or_default() == name)
+        })
+        .map(|package| package.name.clone())
+        .collect()
+}
+
+pub fn validate(configs: &Config, databases: &[SyncDb]) -> (Vec<UnknownName>, Vec<String>) {
+    //! checks every declared package and group against the sync databases and every overlay
+    //! against the overlay directory
+    //! patched packages and overlays are valid packages even if they aren't built yet, packages
+    //! that only exist as provides of other packages are valid as well since pacman installs a
+    //! provider for them
+    //! returns the names that don't exist with the file that declares them and a notice for
+    //! every package that is installed through a provider
+    let packages: Vec<&RepoPackage> = databases
+        .iter()
+        .flat_map(|database| &database.packages)
+        .collect();
+    let mut groups: Vec<String> = packages
+        .iter()
+        .flat_map(|package| package.groups.iter().cloned())
+        .collect();
+    groups.sort();
+    groups.dedup();
+
+    let mut unknown: Vec<UnknownName> = Vec::new();
+    let mut notices: Vec<String> = Vec::new();
+    let mut report = |kind: &str, name: &str, suggestions: Vec<String>| {
+        unknown.push(UnknownName {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            file: configs.declared_in(kind, name),
+            suggestions,
+        })
+    };
+
+    for name in &configs.packages {
+        let built_locally = configs.overlays.contains(name)
+            || configs
+                .patches
+                .iter()
+                .any(|patches| patches.contains_key(name));
+        if built_locally || packages.iter().any(|package| &package.name == name) {
+            continue;
+        }
+        let providers = providers(name, &packages);
+        if providers.is_empty() {
+            report("package", name, package_suggestions(name, &packages));
+        } else {
+            notices.push(format!(
+                "package {} in {} is provided by {}",
+                name,
+                configs.declared_in("package", name),
+                providers.join(", ")
+            ));
+        }
+    }
+
+    for group in &configs.package_groups {
+        if !groups.iter().any(|name| name == group.name()) {
+            report(
+                "group",
+                group.name(),
+                close_matches(group.name(), groups.iter()),
+            );
+        }
+    }
+
+    for name in &configs.overlays {
+        let overlay_dir = format!("{}/{}", configs.overlay_dir, name);
+        if !Path::new(&overlay_dir).join("PKGBUILD").is_file() {
+            report(
+                "overlay",
+                name,
+                vec![format!("no PKGBUILD in {}", overlay_dir)],
+            );
+        }
+    }
+
+    (unknown, notices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::syncdb;
+
+    const SYNC_DB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sync");
+
+    fn databases() -> Vec<SyncDb> {
+        syncdb::read_sync_dbs(SYNC_DB, &["core".to_string(), "extra".to_string()])
+    }
+
+    fn config(packages: &str, groups: &str, overlays: &str) -> Config {
+        let mut config: Config = toml::from_str(&format!(
+            r#"
+            name = "test"
+            build_dir = "/tmp"
+            patch_dir = "/tmp"
+            overlay_dir = "{}/tests/fixtures/pkgbuild"
+            local_repo = "none"
+            packages = [{}]
+            package_groups = [{}]
+            patches = [{{ gcc-libs = ["0001-fix.patch"] }}]
+            overlays = [{}]
+            pacconfig = "/etc/pacman.conf"
+            mirrorlist = "/etc/pacman.d/mirrorlist"
+            snapshot = "none"
+            configs = []
+            imports = []
+            "#,
+            env!("CARGO_MANIFEST_DIR"),
+            packages,
+            groups,
+            overlays
+        ))
+        .unwrap();
+        config.record_origins("test.toml");
+        config
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("python", "python"), 0);
+        assert_eq!(edit_distance("pythn", "python"), 1);
+        assert_eq!(edit_distance("pyhton", "python"), 2);
+        assert_eq!(edit_distance("glibc", "glib"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn validate_declared_names() {
+        let configs = config(
+            r#""glibc", "pythn", "python3-legacy", "gcc-libs", "nano""#,
+            r#""gnome", "gnom", "xorg""#,
+            r#""nano", "missing""#,
+        );
+        let (unknown, notices) = validate(&configs, &databases());
+        let messages: Vec<String> = unknown.iter().map(|name| name.message()).collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "unknown package pythn in test.toml --> did you mean python?",
+                "unknown package python3-legacy in test.toml --> replaced by python",
+                "unknown group gnom in test.toml --> did you mean gnome?",
+                "unknown group xorg in test.toml",
+                format!(
+                    "unknown overlay missing in test.toml --> no PKGBUILD in {}/tests/fixtures/pkgbuild/missing",
+                    env!("CARGO_MANIFEST_DIR")
+                )
+                .as_str(),
+            ]
+        );
+        assert!(notices.is_empty());
+    }
+
+    #[test]
+    fn validate_accepts_provided_names() {
+        let configs = config(r#""python3""#, "", "");
+        let (unknown, notices) = validate(&configs, &databases());
+
+        assert!(unknown.is_empty());
+        assert_eq!(
+            notices,
+            vec!["package python3 in test.toml is provided by python"]
+        );
+    }
+
+    #[test]
+    fn validate_without_databases() {
+        // every package and group is unknown, the overlays are still checked
+        let configs = config(r#""glibc""#, r#""gnome""#, r#""nano""#);
+        let (unknown, _) = validate(&configs, &[]);
+        let names: Vec<&str> = unknown.iter().map(|name| name.name.as_str()).collect();
+        assert_eq!(names, vec!["glibc", "gnome"]);
+    }
+}
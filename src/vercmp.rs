@@ -0,0 +1,239 @@
+use std::cmp::Ordering;
+
+fn parse_evr(version: &str) -> (&str, &str, Option<&str>) {
+    //! takes a version string epoch:pkgver-pkgrel and splits it into its parts
+    //! the epoch defaults to 0 and the release is optional
+    let digits = version
+        .bytes()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+
+    let (epoch, rest) = if version[digits..].starts_with(':') {
+        let epoch = &version[..digits];
+        (
+            if epoch.is_empty() { "0" } else { epoch },
+            &version[digits + 1..],
+        )
+    } else {
+        ("0", version)
+    };
+
+    match rest.rsplit_once('-') {
+        Some((pkgver, pkgrel)) => (epoch, pkgver, Some(pkgrel)),
+        None => (epoch, rest, None),
+    }
+}
+
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    //! compares two version segments the same way as rpmvercmp in libalpm
+    //! the strings are split into alternating blocks of digits and letters that are compared
+    //! one after another, all other characters are separators
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let one = a.as_bytes();
+    let two = b.as_bytes();
+    // start of the current segment and position after the previous one
+    let (mut i, mut j) = (0, 0);
+    let (mut ptr1, mut ptr2) = (0, 0);
+
+    while i < one.len() && j < two.len() {
+        // skip the separators
+        while i < one.len() && !one[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        while j < two.len() && !two[j].is_ascii_alphanumeric() {
+            j += 1;
+        }
+
+        // one of the strings ran out
+        if i >= one.len() || j >= two.len() {
+            break;
+        }
+
+        // different separator lengths decide the comparison
+        if i - ptr1 != j - ptr2 {
+            return (i - ptr1).cmp(&(j - ptr2));
+        }
+
+        ptr1 = i;
+        ptr2 = j;
+
+        // take the next block of digits or letters from both strings
+        let is_num = one[ptr1].is_ascii_digit();
+        if is_num {
+            while ptr1 < one.len() && one[ptr1].is_ascii_digit() {
+                ptr1 += 1;
+            }
+            while ptr2 < two.len() && two[ptr2].is_ascii_digit() {
+                ptr2 += 1;
+            }
+        } else {
+            while ptr1 < one.len() && one[ptr1].is_ascii_alphabetic() {
+                ptr1 += 1;
+            }
+            while ptr2 < two.len() && two[ptr2].is_ascii_alphabetic() {
+                ptr2 += 1;
+            }
+        }
+
+        // the blocks are of different types, numeric blocks are always newer
+        if j == ptr2 {
+            return if is_num {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let mut block1 = &one[i..ptr1];
+        let mut block2 = &two[j..ptr2];
+
+        if is_num {
+            // leading zeros are ignored and the longer number wins
+            while block1.first() == Some(&b'0') {
+                block1 = &block1[1..];
+            }
+            while block2.first() == Some(&b'0') {
+                block2 = &block2[1..];
+            }
+            if block1.len() != block2.len() {
+                return block1.len().cmp(&block2.len());
+            }
+        }
+
+        match block1.cmp(block2) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+
+        i = ptr1;
+        j = ptr2;
+    }
+
+    if i >= one.len() && j >= two.len() {
+        return Ordering::Equal;
+    }
+
+    // a remaining alpha block is always older than an empty string, everything else is newer
+    if (i >= one.len() && !two[j].is_ascii_alphabetic())
+        || (i < one.len() && one[i].is_ascii_alphabetic())
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+pub fn vercmp(a: &str, b: &str) -> Ordering {
+    //! compares two package versions (epoch:pkgver-pkgrel) with the semantics of pacman's vercmp
+    //! returns Greater if a is newer than b, Less if it is older and Equal if both are the same
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let (epoch1, version1, release1) = parse_evr(a);
+    let (epoch2, version2, release2) = parse_evr(b);
+
+    let mut ordering = rpmvercmp(epoch1, epoch2);
+    if ordering == Ordering::Equal {
+        ordering = rpmvercmp(version1, version2);
+        // the release is only compared if both versions have one
+        if ordering == Ordering::Equal {
+            if let (Some(release1), Some(release2)) = (release1, release2) {
+                ordering = rpmvercmp(release1, release2);
+            }
+        }
+    }
+
+    ordering
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vercmp;
+    use std::cmp::Ordering;
+
+    // test table of pacman's test/util/vercmptest.sh
+    const VERCMP_TESTS: &[(&str, &str, i32)] = &[
+        // all similar length, no pkgrel
+        ("1.5.0", "1.5.0", 0),
+        ("1.5.1", "1.5.0", 1),
+        // mixed length
+        ("1.5.1", "1.5", 1),
+        // with pkgrel, simple
+        ("1.5.0-1", "1.5.0-1", 0),
+        ("1.5.0-1", "1.5.0-2", -1),
+        ("1.5.0-1", "1.5.1-1", -1),
+        ("1.5.0-2", "1.5.1-1", -1),
+        // with pkgrel, mixed lengths
+        ("1.5-1", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-2", -1),
+        // mixed pkgrel inclusion
+        ("1.5", "1.5-1", 0),
+        ("1.5-1", "1.5", 0),
+        ("1.1-1", "1.1", 0),
+        ("1.0-1", "1.1", -1),
+        ("1.1-1", "1.0", 1),
+        // alphanumeric versions
+        ("1.5b-1", "1.5-1", -1),
+        ("1.5b", "1.5", -1),
+        ("1.5b-1", "1.5", -1),
+        ("1.5b", "1.5.1", -1),
+        // from the manpage
+        ("1.0a", "1.0alpha", -1),
+        ("1.0alpha", "1.0b", -1),
+        ("1.0b", "1.0beta", -1),
+        ("1.0beta", "1.0rc", -1),
+        ("1.0rc", "1.0", -1),
+        // alpha-dotted versions
+        ("1.5.a", "1.5", 1),
+        ("1.5.b", "1.5.a", 1),
+        ("1.5.1", "1.5.b", 1),
+        // alpha dots and dashes
+        ("1.5.b-1", "1.5.b", 0),
+        ("1.5-1", "1.5.b", -1),
+        // same/similar content, differing separators
+        ("2.0", "2_0", 0),
+        ("2.0_a", "2_0.a", 0),
+        ("2.0a", "2.0.a", -1),
+        ("2___a", "2_a", 1),
+        // epoch included version comparisons
+        ("0:1.0", "0:1.0", 0),
+        ("0:1.0", "0:1.1", -1),
+        ("1:1.0", "0:1.0", 1),
+        ("1:1.0", "0:1.1", 1),
+        ("1:1.0", "2:1.1", -1),
+        // epoch + sometimes present pkgrel
+        ("1:1.0", "0:1.0-1", 1),
+        ("1:1.0-1", "0:1.1-1", 1),
+        // epoch included on one version
+        ("0:1.0", "1.0", 0),
+        ("0:1.0", "1.1", -1),
+        ("0:1.1", "1.0", 1),
+        ("1:1.0", "1.0", 1),
+        ("1:1.0", "1.1", 1),
+        ("1:1.1", "1.1", 1),
+    ];
+
+    fn ordering(expected: i32) -> Ordering {
+        expected.cmp(&0)
+    }
+
+    #[test]
+    fn pacman_vercmp_table() {
+        for (a, b, expected) in VERCMP_TESTS {
+            assert_eq!(vercmp(a, b), ordering(*expected), "vercmp {} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn pacman_vercmp_table_reversed() {
+        // like vercmptest.sh, every comparison is checked in both directions
+        for (a, b, expected) in VERCMP_TESTS {
+            assert_eq!(vercmp(b, a), ordering(-*expected), "vercmp {} {}", b, a);
+        }
+    }
+}